@@ -1,17 +1,356 @@
 use bootloader::DiskImageBuilder;
-use std::{env, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// newc形式のcpioのモード
+const MODE_DIRECTORY: u32 = 0o040755;
+const MODE_FILE: u32 = 0o100644;
+const MODE_EXECUTABLE: u32 = 0o100755;
+
+// カーネルのシンボル表の形式。kernel/src/backtrace.rsと合わせる
+const SYMBOL_TABLE_SECTION: &str = ".ksyms";
+const SYMBOL_TABLE_MAGIC: &[u8; 4] = b"KSYM";
+const SYMBOL_TABLE_HEADER_SIZE: usize = 16;
+const SYMBOL_ENTRY_SIZE: usize = 16;
 
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let kernel_path = embed_symbols(Path::new(&kernel_path), &out_dir);
+    let mut disk_builder = DiskImageBuilder::new(kernel_path);
 
-    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let uefi_path = out_dir.join("rost-uefi.img");
     let bios_path = out_dir.join("rost-bios.img");
 
+    // ramdisk/ディレクトリとユーザプログラムをcpioアーカイブにまとめてramdiskとして渡す
+    let ramdisk_path = out_dir.join("ramdisk.cpio");
+    fs::write(&ramdisk_path, build_ramdisk()).unwrap();
+    disk_builder.set_ramdisk(ramdisk_path);
+
     disk_builder.create_uefi_image(&uefi_path).unwrap();
     disk_builder.create_bios_image(&bios_path).unwrap();
 
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_path.display());
 }
+
+fn build_ramdisk() -> Vec<u8> {
+    let mut archive = CpioWriter::default();
+
+    let ramdisk_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("ramdisk");
+    println!("cargo:rerun-if-changed={}", ramdisk_dir.display());
+    if ramdisk_dir.is_dir() {
+        add_directory(&mut archive, &ramdisk_dir, "");
+    }
+
+    // devfsとtmpfsのマウントポイント
+    archive.add("dev", MODE_DIRECTORY, &[]);
+    archive.add("tmp", MODE_DIRECTORY, &[]);
+
+    archive.add("bin", MODE_DIRECTORY, &[]);
+    let init = fs::read(env::var("CARGO_BIN_FILE_USERLAND_init").unwrap()).unwrap();
+    archive.add("bin/init", MODE_EXECUTABLE, &init);
+
+    archive.finish()
+}
+
+fn add_directory(archive: &mut CpioWriter, dir: &Path, prefix: &str) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = format!(
+            "{}{}",
+            prefix,
+            path.file_name().unwrap().to_str().expect("non UTF-8 file name")
+        );
+        if path.is_dir() {
+            archive.add(&name, MODE_DIRECTORY, &[]);
+            add_directory(archive, &path, &format!("{}/", name));
+        } else {
+            archive.add(&name, MODE_FILE, &fs::read(&path).unwrap());
+        }
+    }
+}
+
+#[derive(Default)]
+struct CpioWriter {
+    data: Vec<u8>,
+    next_inode: u32,
+}
+
+impl CpioWriter {
+    fn add(&mut self, name: &str, mode: u32, contents: &[u8]) {
+        self.next_inode += 1;
+        let nlink = if mode == MODE_DIRECTORY { 2 } else { 1 };
+        let fields = [
+            self.next_inode,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            0, // mtime
+            contents.len() as u32,
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name.len() as u32 + 1,
+            0, // check
+        ];
+
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.pad();
+        self.data.extend_from_slice(contents);
+        self.pad();
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.add("TRAILER!!!", 0, &[]);
+        self.data
+    }
+
+    // ヘッダ+名前とデータはそれぞれ4バイト境界に揃える
+    fn pad(&mut self) {
+        while self.data.len() % 4 != 0 {
+            self.data.push(0);
+        }
+    }
+}
+
+/// カーネルのELFの.symtabから関数のシンボルを集め、.ksymsセクションに書き込んだコピーを作る
+///
+/// 表は先頭から次の並び(リトルエンディアン)
+///
+/// | "KSYM" | シンボルの数(u32) | .ksymsセクションのリンク時のアドレス(u64) |
+/// | アドレス(u64), 大きさ(u32), 名前の位置(u32) | ... (アドレス順)
+/// | NUL終端の名前 | ...
+fn embed_symbols(kernel_path: &Path, out_dir: &Path) -> PathBuf {
+    let mut elf = fs::read(kernel_path).unwrap();
+    let sections = Section::all(&elf);
+    let Some(table) = sections
+        .iter()
+        .find(|section| section.name == SYMBOL_TABLE_SECTION)
+    else {
+        println!("cargo:warning=kernel has no {SYMBOL_TABLE_SECTION} section, backtraces will not show symbol names");
+        return kernel_path.to_path_buf();
+    };
+    let symtab = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .expect("kernel has no symbol table");
+    let strtab = &sections[symtab.link as usize];
+
+    let mut symbols: Vec<(u64, u32, String)> = elf[symtab.range()]
+        .chunks_exact(24)
+        .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+        .map(|symbol| {
+            let name = read_u32(symbol, 0) as usize;
+            let name = &elf[strtab.offset as usize + name..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+            let value = read_u64(symbol, 8);
+            let size = read_u64(symbol, 16) as u32;
+            (value, size, demangle(&String::from_utf8_lossy(name)))
+        })
+        .filter(|(value, _, _)| *value != 0)
+        .collect();
+    symbols.sort_by_key(|(value, _, _)| *value);
+    symbols.dedup_by_key(|(value, _, _)| *value);
+
+    // 入りきらないときは、入るところまでで打ち切る
+    let capacity = table.size as usize;
+    let mut count = 0;
+    let mut names_len = 0;
+    for (_, _, name) in &symbols {
+        let needed = SYMBOL_TABLE_HEADER_SIZE
+            + (count + 1) * SYMBOL_ENTRY_SIZE
+            + names_len
+            + name.len()
+            + 1;
+        if needed > capacity {
+            println!(
+                "cargo:warning={} of {} kernel symbols do not fit in {} ({} bytes)",
+                symbols.len() - count,
+                symbols.len(),
+                SYMBOL_TABLE_SECTION,
+                capacity
+            );
+            break;
+        }
+        count += 1;
+        names_len += name.len() + 1;
+    }
+    symbols.truncate(count);
+
+    let mut data = Vec::with_capacity(capacity);
+    data.extend_from_slice(SYMBOL_TABLE_MAGIC);
+    data.extend_from_slice(&(count as u32).to_le_bytes());
+    data.extend_from_slice(&table.addr.to_le_bytes());
+    let mut name_offset = 0;
+    for (value, size, name) in &symbols {
+        data.extend_from_slice(&value.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&(name_offset as u32).to_le_bytes());
+        name_offset += name.len() + 1;
+    }
+    for (_, _, name) in &symbols {
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+    }
+    data.resize(capacity, 0);
+    let range = table.range();
+    elf[range].copy_from_slice(&data);
+
+    let path = out_dir.join("kernel-with-symbols");
+    fs::write(&path, elf).unwrap();
+    path
+}
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+// ELF64のセクションヘッダのうち使うところ
+struct Section {
+    name: String,
+    kind: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+impl Section {
+    fn all(elf: &[u8]) -> Vec<Section> {
+        assert_eq!(&elf[..4], b"\x7fELF", "kernel is not an ELF file");
+        let shoff = read_u64(elf, 0x28) as usize;
+        let shentsize = read_u16(elf, 0x3a) as usize;
+        let shnum = read_u16(elf, 0x3c) as usize;
+        let shstrndx = read_u16(elf, 0x3e) as usize;
+        let header = |index: usize| &elf[shoff + index * shentsize..][..shentsize];
+        let names = read_u64(header(shstrndx), 24) as usize;
+
+        (0..shnum)
+            .map(|index| {
+                let header = header(index);
+                let name = &elf[names + read_u32(header, 0) as usize..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+                Section {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    kind: read_u32(header, 4),
+                    addr: read_u64(header, 16),
+                    offset: read_u64(header, 24),
+                    size: read_u64(header, 32),
+                    link: read_u32(header, 40),
+                }
+            })
+            .collect()
+    }
+
+    fn range(&self) -> std::ops::Range<usize> {
+        self.offset as usize..(self.offset + self.size) as usize
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// Rustの旧形式のマングリング(_ZN...E)を読める形に戻す。末尾のハッシュは落とす
+// それ以外の名前はそのまま返す
+fn demangle(symbol: &str) -> String {
+    let Some(mut rest) = symbol.strip_prefix("_ZN") else {
+        return symbol.to_string();
+    };
+    let mut components = Vec::new();
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()) {
+        if len_end == 0 {
+            break;
+        }
+        let len: usize = rest[..len_end].parse().unwrap();
+        let Some(component) = rest.get(len_end..len_end + len) else {
+            return symbol.to_string();
+        };
+        components.push(component);
+        rest = &rest[len_end + len..];
+    }
+    if rest != "E" || components.is_empty() {
+        return symbol.to_string();
+    }
+    if let Some(last) = components.last() {
+        if last.len() == 17
+            && last.starts_with('h')
+            && last[1..].chars().all(|c| c.is_ascii_hexdigit())
+        {
+            components.pop();
+        }
+    }
+    components
+        .iter()
+        .map(|component| unescape(component))
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+// $LT$などのエスケープを元の文字に戻す
+fn unescape(component: &str) -> String {
+    // 先頭が$になるときは_を前に付けてある
+    let component = match component.strip_prefix('_') {
+        Some(rest) if rest.starts_with('$') => rest,
+        _ => component,
+    };
+    let mut result = String::new();
+    let mut rest = component;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            result.push_str("::");
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('$') {
+            if let Some(end) = after.find('$') {
+                let decoded = match &after[..end] {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    escape => escape
+                        .strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32),
+                };
+                if let Some(c) = decoded {
+                    result.push(c);
+                    rest = &after[end + 1..];
+                    continue;
+                }
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    result
+}
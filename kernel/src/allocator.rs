@@ -1,20 +1,46 @@
 // local変数とstatic変数の制約を回避するために、変数を格納する
 // ための第三の領域であるヒープがある
 
+use core::{alloc::Layout, ptr};
+
+#[cfg(not(feature = "slab-allocator"))]
 use fixed_size_block::FixedSizeBlockAllocator;
+use linked_list_allocator::Heap;
+#[cfg(feature = "slab-allocator")]
+use slab::SlabAllocator;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTable,
+        PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+use crate::sync::irq_spinlock::{IrqSpinlock, IrqSpinlockGuard};
+
+use crate::{
+    memory::{address_space, phys_to_virt, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE},
+    println,
+};
+
 pub mod bump;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
 pub mod fixed_size_block;
+pub mod slab;
+#[cfg(feature = "alloc-tracking")]
+pub mod tracking;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
 
+// 足りなくなったらこの大きさまでフレームをマップして伸ばす
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16MiB
+
+// 一度に伸ばす最小の大きさ
+const HEAP_GROW_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -40,30 +66,158 @@ pub fn init_heap(
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
+    #[cfg(feature = "slab-allocator")]
+    register_caches(&mut ALLOCATOR.lock());
 
     Ok(())
 }
 
+// 確保した場所の記録はfixed_size_blockにしか無い
+#[cfg(all(feature = "slab-allocator", feature = "alloc-tracking"))]
+compile_error!("alloc-tracking is not supported with slab-allocator");
+
+// グローバルアロケータはslab-allocatorフィーチャで選ぶ
+#[cfg(not(feature = "slab-allocator"))]
+pub type KernelAllocator = FixedSizeBlockAllocator;
+#[cfg(feature = "slab-allocator")]
+pub type KernelAllocator = SlabAllocator;
+
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+pub static ALLOCATOR: Locked<KernelAllocator> = Locked::new(KernelAllocator::new());
+
+// heap-debugフィーチャではレッドゾーンとポイズンで破壊を調べるアロケータで包む
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+pub static ALLOCATOR: debug::DebugAllocator<Locked<KernelAllocator>> =
+    debug::DebugAllocator::new(Locked::new(KernelAllocator::new()));
+
+// よく確保するカーネルオブジェクトの名前付きキャッシュ
+// 同じレイアウトのオブジェクトを確保する前に登録するため、ヒープの初期化の直後に呼ぶ
+#[cfg(feature = "slab-allocator")]
+fn register_caches(allocator: &mut SlabAllocator) {
+    use crate::{memory::IoBoxInner, process::Process, task::Task, xhci::rings::TrbRing};
+
+    allocator.register_cache("process", Layout::new::<Process>());
+    allocator.register_cache("task", Layout::new::<Task>());
+    allocator.register_cache("trb-ring", Layout::new::<IoBoxInner<TrbRing>>());
+}
+
+/// 現在のヒープの大きさ
+pub fn heap_size() -> usize {
+    ALLOCATOR.lock().heap_size()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GrowError {
+    // HEAP_MAX_SIZEまで伸ばしきった
+    LimitReached,
+    // フレームアロケータがまだ無い
+    FrameAllocatorUnavailable,
+    // フレームアロケータのロックを持ったままヒープを使おうとした
+    FrameAllocatorReentered,
+    // 物理フレームが足りない
+    OutOfFrames,
+}
+
+/// heapから確保する。空きが無ければヒープを伸ばしてもう一度試す
+/// 伸ばせなければnullを返し、alloc_error_handlerに任せる
+pub(crate) fn alloc_from_heap(heap: &mut Heap, layout: Layout) -> *mut u8 {
+    if let Ok(ptr) = heap.allocate_first_fit(layout) {
+        return ptr.as_ptr();
+    }
+    if grow_heap(heap, layout.size() + layout.align()).is_err() {
+        return ptr::null_mut();
+    }
+    match heap.allocate_first_fit(layout) {
+        Ok(ptr) => ptr.as_ptr(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// ヒープの末尾に少なくともmin_sizeバイトのページをマップしてheapを伸ばす
+///
+/// フレームアロケータをロックしたままヒープを使っているところで足りなくなったときは、
+/// 自分を待つことになるので失敗する
+fn grow_heap(heap: &mut Heap, min_size: usize) -> Result<(), GrowError> {
+    let available = HEAP_MAX_SIZE.saturating_sub(heap.size());
+    let size = align_up(min_size.max(HEAP_GROW_STEP), PAGE_SIZE).min(available);
+    if size < min_size || size == 0 {
+        return Err(GrowError::LimitReached);
+    }
+
+    let frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .ok_or(GrowError::FrameAllocatorUnavailable)?;
+    // アロケータのロックを持っていて割り込みは禁止されているので、ロック中なら持っているのは自分
+    if frame_allocator.is_locked() {
+        return Err(GrowError::FrameAllocatorReentered);
+    }
+    let mut frame_allocator = frame_allocator.lock();
+    let level_4_frame = KERNEL_PAGE_TABLE
+        .get()
+        .ok_or(GrowError::FrameAllocatorUnavailable)?;
+    // ヒープはブート時のレベル4テーブルの共有部分にあるので、そこに足せば全プロセスから見える
+    let level_4_table =
+        unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>() };
+    let mut mapper = unsafe { address_space::mapper(level_4_table) };
+
+    let top = heap.top() as u64;
+    let mut mapped = 0;
+    while mapped < size {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(top + mapped as u64));
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            break;
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += PAGE_SIZE;
+    }
+
+    // 途中までしかマップできなくても、マップした分はヒープに入れる
+    if mapped > 0 {
+        unsafe { heap.extend(mapped) };
+    }
+    if mapped < min_size {
+        return Err(GrowError::OutOfFrames);
+    }
+    Ok(())
+}
 
 #[alloc_error_handler]
-fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+fn alloc_error_handler(layout: Layout) -> ! {
+    println!(
+        "out of memory: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    println!(
+        "heap: {} / {} KiB",
+        ALLOCATOR.lock().heap_size() / 1024,
+        HEAP_MAX_SIZE / 1024
+    );
     panic!("allocation error: {:?}", layout)
 }
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSpinlock<A>,
 }
 
 impl<A> Locked<A> {
+    #[track_caller]
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSpinlock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSpinlockGuard<A> {
         self.inner.lock()
     }
 }
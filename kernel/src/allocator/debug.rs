@@ -0,0 +1,193 @@
+//! ヒープの破壊を見つけるためのデバッグ用のアロケータ(heap-debugフィーチャ)
+//!
+//! 確保した領域の前後にレッドゾーンを置き、解放するときに書き換えられていないか調べる
+//! 解放した領域はポイズンで埋めてしばらく手元に置き(quarantine)、その間に書き込まれて
+//! いないかを内側のアロケータに返すときに調べる
+//!
+//! | ヘッダ | 前のレッドゾーン | 確保した領域 | 後ろのレッドゾーン |
+//!                              ^ 返すポインタ
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, mem,
+    ops::Deref,
+};
+use spin::Mutex;
+
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+// 確保した直後の領域と、解放した領域を埋める値
+const UNINIT_BYTE: u8 = 0xcd;
+const POISON_BYTE: u8 = 0xdd;
+const ALIVE: u64 = 0xa110_c8ed_a110_c8ed;
+const FREED: u64 = 0xdead_f4ee_dead_f4ee;
+const QUARANTINE_SIZE: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+pub struct DebugAllocator<A> {
+    inner: A,
+    // 解放されて、まだ内側のアロケータに返していない領域
+    quarantine: Mutex<Quarantine>,
+}
+
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+}
+
+// 統計などは内側のアロケータのものをそのまま使えるようにする
+impl<A> Deref for DebugAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+// 利用者のlayoutに対して、内側のアロケータから確保する大きさと、先頭から返すポインタまでの距離
+fn outer_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let prefix = (HEADER_SIZE + RED_ZONE).next_multiple_of(align);
+    let size = prefix + layout.size() + RED_ZONE;
+    (Layout::from_size_align(size, align).unwrap(), prefix)
+}
+
+unsafe fn header<'a>(ptr: *mut u8) -> &'a mut Header {
+    &mut *ptr.sub(RED_ZONE + HEADER_SIZE).cast::<Header>()
+}
+
+// [start, start + len)がbyteで埋まっていなければ、最初に違っていたところの位置と値を返す
+unsafe fn find_mismatch(start: *const u8, len: usize, byte: u8) -> Option<(usize, u8)> {
+    (0..len)
+        .map(|offset| (offset, start.add(offset).read()))
+        .find(|&(_, found)| found != byte)
+}
+
+// 確保できなくなっているかもしれないので、ヒープを使わずに報告する
+fn corrupted(ptr: *mut u8, layout: Layout, what: fmt::Arguments) -> ! {
+    panic!(
+        "heap corruption: {} at {:#x} (size {}, align {})",
+        what,
+        ptr as usize,
+        layout.size(),
+        layout.align()
+    )
+}
+
+// レッドゾーンが書き換えられていないか調べる
+unsafe fn check_red_zones(ptr: *mut u8, layout: Layout) {
+    if let Some((offset, found)) = find_mismatch(ptr.sub(RED_ZONE), RED_ZONE, RED_ZONE_BYTE) {
+        corrupted(
+            ptr,
+            layout,
+            format_args!(
+                "buffer underflow, byte {} before the block is {:#04x}",
+                RED_ZONE - offset,
+                found
+            ),
+        );
+    }
+    if let Some((offset, found)) = find_mismatch(ptr.add(layout.size()), RED_ZONE, RED_ZONE_BYTE) {
+        corrupted(
+            ptr,
+            layout,
+            format_args!(
+                "buffer overflow, byte {} after the block is {:#04x}",
+                offset, found
+            ),
+        );
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, prefix) = outer_layout(layout);
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(prefix);
+        ptr.sub(RED_ZONE + HEADER_SIZE)
+            .cast::<Header>()
+            .write(Header {
+                magic: ALIVE,
+                size: layout.size(),
+                align: layout.align(),
+            });
+        ptr.sub(RED_ZONE).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        ptr.write_bytes(UNINIT_BYTE, layout.size());
+        ptr.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = header(ptr);
+        match header.magic {
+            ALIVE => {}
+            FREED => corrupted(ptr, layout, format_args!("double free")),
+            _ => corrupted(
+                ptr,
+                layout,
+                format_args!("free of an invalid pointer or corrupted header"),
+            ),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            corrupted(
+                ptr,
+                layout,
+                format_args!(
+                    "layout mismatch, allocated with size {} and align {}",
+                    header.size, header.align
+                ),
+            );
+        }
+        check_red_zones(ptr, layout);
+
+        header.magic = FREED;
+        ptr.write_bytes(POISON_BYTE, layout.size());
+
+        // 一番古いものを追い出して内側のアロケータに返す
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let next = quarantine.next;
+            quarantine.next = (next + 1) % QUARANTINE_SIZE;
+            quarantine.blocks[next].replace((ptr as usize, layout))
+        };
+        if let Some((ptr, layout)) = evicted {
+            let ptr = ptr as *mut u8;
+            if let Some((offset, found)) = find_mismatch(ptr, layout.size(), POISON_BYTE) {
+                corrupted(
+                    ptr,
+                    layout,
+                    format_args!(
+                        "use after free, byte {} of the freed block is {:#04x}",
+                        offset, found
+                    ),
+                );
+            }
+            check_red_zones(ptr, layout);
+            let (outer, prefix) = outer_layout(layout);
+            self.inner.dealloc(ptr.sub(prefix), outer);
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
     mem,
-    ptr::{self, NonNull},
+    ptr::NonNull,
 };
 
-use super::Locked;
+#[cfg(feature = "alloc-tracking")]
+use super::tracking::{self, Allocation, Tracker};
+use super::{alloc_from_heap, Locked};
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -12,17 +14,89 @@ struct ListNode {
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// ブロックの大きさごとの統計。fallbackではblock_sizeは0
+#[derive(Debug, Clone, Copy)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    // 要求された大きさの合計と、その最大値
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    // 空きリストにつながっているブロックの数
+    pub free_blocks: usize,
+}
+
+impl ClassStats {
+    const fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            allocs: 0,
+            frees: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            free_blocks: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.allocs += 1;
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+
+    fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.live_bytes -= size;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub classes: [ClassStats; BLOCK_SIZES.len()],
+    pub fallback: ClassStats,
+    // fallbackのヒープ全体の大きさ、使用中の大きさ、一度に確保できる最大の大きさ
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    /// 空き領域のうち、最大の空きブロックに入っていない割合(%)
+    pub fn fragmentation(&self) -> usize {
+        let free = self.heap_size - self.heap_used;
+        if free == 0 {
+            return 0;
+        }
+        100 - self.largest_free * 100 / free
+    }
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    classes: [ClassStats; BLOCK_SIZES.len()],
+    fallback: ClassStats,
+    #[cfg(feature = "alloc-tracking")]
+    tracker: Tracker,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
+        let mut classes = [ClassStats::new(0); BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            classes[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            classes,
+            fallback: ClassStats::new(0),
+            #[cfg(feature = "alloc-tracking")]
+            tracker: Tracker::new(),
         }
     }
 
@@ -31,10 +105,60 @@ impl FixedSizeBlockAllocator {
             .init(heap_start as *mut u8, heap_size);
     }
 
+    pub fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    pub fn stats(&mut self) -> HeapStats {
+        HeapStats {
+            classes: self.classes,
+            fallback: self.fallback,
+            heap_size: self.fallback_allocator.size(),
+            heap_used: self.fallback_allocator.used(),
+            largest_free: self.largest_free(),
+        }
+    }
+
+    // 一度に確保できる最大の大きさを、実際に確保してみて二分探索で求める
+    // linked_list_allocatorはホールのリストを見せないので確保してみるしかない
+    // fallback_allocatorから直接確保するのでヒープは伸ばさず、確保した分はすぐに返す
+    fn largest_free(&mut self) -> usize {
+        const WORD: usize = mem::size_of::<usize>();
+        let (mut low, mut high) = (0, self.fallback_allocator.free() / WORD);
+        while low < high {
+            let words = (low + high).div_ceil(2);
+            let layout = Layout::from_size_align(words * WORD, WORD).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = words;
+                }
+                Err(_) => high = words - 1,
+            }
+        }
+        low * WORD
+    }
+
+    /// 記録されている確保中の領域
+    #[cfg(feature = "alloc-tracking")]
+    pub fn tracked(&self) -> impl Iterator<Item = &Allocation> {
+        self.tracker.live()
+    }
+
+    /// 次の確保に付ける番号と、記録しきれなかった確保の数
+    #[cfg(feature = "alloc-tracking")]
+    pub fn tracking_state(&self) -> (u64, usize) {
+        (self.tracker.next_sequence(), self.tracker.untracked())
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        alloc_from_heap(&mut self.fallback_allocator, layout)
+    }
+
+    fn stats_for(&mut self, index: Option<usize>) -> &mut ClassStats {
+        match index {
+            Some(index) => &mut self.classes[index],
+            None => &mut self.fallback,
         }
     }
 }
@@ -47,10 +171,12 @@ fn list_index(layout: &Layout) -> Option<usize> {
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let index = list_index(&layout);
+        let ptr = match index {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     allocator.list_heads[index] = node.next.take();
+                    allocator.classes[index].free_blocks -= 1;
                     node as *mut ListNode as *mut u8
                 }
                 None => {
@@ -61,12 +187,24 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             },
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.stats_for(index).record_alloc(layout.size());
+            #[cfg(feature = "alloc-tracking")]
+            allocator
+                .tracker
+                .record(ptr as usize, layout.size(), tracking::callers());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let index = list_index(&layout);
+        allocator.stats_for(index).record_free(layout.size());
+        #[cfg(feature = "alloc-tracking")]
+        allocator.tracker.forget(ptr as usize);
+        match index {
             Some(index) => {
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
@@ -77,6 +215,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 let new_node_ptr = ptr as *mut ListNode;
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.classes[index].free_blocks += 1;
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
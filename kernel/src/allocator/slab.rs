@@ -0,0 +1,356 @@
+//! スラブアロケータ
+//!
+//! 同じ大きさのオブジェクトを連続したページ(スラブ)に詰めて確保するキャッシュを持つ
+//! 大きさごとのキャッシュ(kmalloc-8など)に加えて、よく確保するカーネルオブジェクト用に
+//! レイアウトが一致するものだけを入れる名前付きキャッシュを登録できる
+//! 全部空いたスラブはキャッシュごとに1つだけ残し、残りはヒープに返す
+//!
+//! スラブはスラブの大きさの境界に揃えて確保し、先頭にSlabを置く
+//! 解放するオブジェクトのアドレスを切り捨てればSlabが見つかる
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use super::{alloc_from_heap, Locked};
+
+const SIZE_CLASSES: &[(&str, usize)] = &[
+    ("kmalloc-8", 8),
+    ("kmalloc-16", 16),
+    ("kmalloc-32", 32),
+    ("kmalloc-64", 64),
+    ("kmalloc-128", 128),
+    ("kmalloc-256", 256),
+    ("kmalloc-512", 512),
+    ("kmalloc-1024", 1024),
+    ("kmalloc-2048", 2048),
+];
+pub const MAX_CACHES: usize = 16;
+const MIN_SLAB_SIZE: usize = 4096;
+const MAX_SLAB_SIZE: usize = 64 * 1024;
+// 1つのスラブに少なくともこれだけのオブジェクトが入るようにスラブを大きくする
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+// ヒープに返さずに残しておく空のスラブの数
+const KEEP_EMPTY_SLABS: usize = 1;
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct Slab {
+    next: Option<NonNull<Slab>>,
+    prev: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub objects_per_slab: usize,
+    // 空きの無いスラブ、一部が使われているスラブ、空のスラブの数
+    pub full_slabs: usize,
+    pub partial_slabs: usize,
+    pub empty_slabs: usize,
+    pub in_use: usize,
+    // ヒープに返したスラブの数
+    pub reclaimed: usize,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    // オブジェクト1つ分の大きさと揃え
+    layout: Layout,
+    // 名前付きキャッシュはこのレイアウトと一致するものだけを入れる
+    // 大きさごとのキャッシュ(None)はlayoutに収まるものを入れる
+    requested: Option<Layout>,
+    slab_size: usize,
+    objects_offset: usize,
+    objects_per_slab: usize,
+    // 空きのあるスラブと、空きの無いスラブのリスト
+    partial: Option<NonNull<Slab>>,
+    full: Option<NonNull<Slab>>,
+    full_slabs: usize,
+    partial_slabs: usize,
+    empty_slabs: usize,
+    in_use: usize,
+    reclaimed: usize,
+}
+
+impl SlabCache {
+    const fn new(name: &'static str, layout: Layout, requested: Option<Layout>) -> Self {
+        let align = if layout.align() > mem::align_of::<FreeObject>() {
+            layout.align()
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let size = if layout.size() > mem::size_of::<FreeObject>() {
+            layout.size()
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let object_size = size.next_multiple_of(align);
+        let objects_offset = mem::size_of::<Slab>().next_multiple_of(align);
+        let mut slab_size = MIN_SLAB_SIZE;
+        while slab_size < MAX_SLAB_SIZE
+            && (slab_size < objects_offset + object_size
+                || (slab_size - objects_offset) / object_size < MIN_OBJECTS_PER_SLAB)
+        {
+            slab_size *= 2;
+        }
+        let objects_per_slab = slab_size.saturating_sub(objects_offset) / object_size;
+        Self {
+            name,
+            layout: unsafe { Layout::from_size_align_unchecked(object_size, align) },
+            requested,
+            slab_size,
+            objects_offset,
+            objects_per_slab,
+            partial: None,
+            full: None,
+            full_slabs: 0,
+            partial_slabs: 0,
+            empty_slabs: 0,
+            in_use: 0,
+            reclaimed: 0,
+        }
+    }
+
+    fn accepts(&self, layout: &Layout) -> bool {
+        if self.objects_per_slab == 0 {
+            return false;
+        }
+        match self.requested {
+            Some(requested) => *layout == requested,
+            None => layout.size() <= self.layout.size() && layout.align() <= self.layout.align(),
+        }
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            object_size: self.layout.size(),
+            slab_size: self.slab_size,
+            objects_per_slab: self.objects_per_slab,
+            full_slabs: self.full_slabs,
+            partial_slabs: self.partial_slabs,
+            empty_slabs: self.empty_slabs,
+            in_use: self.in_use,
+            reclaimed: self.reclaimed,
+        }
+    }
+
+    // ヒープから新しいスラブを確保し、全部のオブジェクトを空きリストにつないでpartialに入れる
+    fn grow(&mut self, heap: &mut linked_list_allocator::Heap) -> Option<NonNull<Slab>> {
+        let slab = NonNull::new(alloc_from_heap(heap, self.slab_layout()))?.cast::<Slab>();
+        let mut free = None;
+        for index in (0..self.objects_per_slab).rev() {
+            let object = unsafe {
+                slab.cast::<u8>()
+                    .add(self.objects_offset + index * self.layout.size())
+                    .cast::<FreeObject>()
+            };
+            unsafe { object.as_ptr().write(FreeObject { next: free }) };
+            free = Some(object);
+        }
+        unsafe {
+            slab.as_ptr().write(Slab {
+                next: None,
+                prev: None,
+                free,
+                in_use: 0,
+            })
+        };
+        link(&mut self.partial, slab);
+        self.empty_slabs += 1;
+        Some(slab)
+    }
+
+    fn alloc(&mut self, heap: &mut linked_list_allocator::Heap) -> *mut u8 {
+        let slab = match self.partial {
+            Some(slab) => slab,
+            None => match self.grow(heap) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let Some(object) = slab_ref.free else {
+            return ptr::null_mut();
+        };
+        slab_ref.free = unsafe { object.as_ref().next };
+
+        if slab_ref.in_use == 0 {
+            self.empty_slabs -= 1;
+            self.partial_slabs += 1;
+        }
+        slab_ref.in_use += 1;
+        self.in_use += 1;
+        if slab_ref.free.is_none() {
+            unlink(&mut self.partial, slab);
+            link(&mut self.full, slab);
+            self.partial_slabs -= 1;
+            self.full_slabs += 1;
+        }
+        object.as_ptr().cast()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, heap: &mut linked_list_allocator::Heap) {
+        let slab = NonNull::new_unchecked((ptr as usize & !(self.slab_size - 1)) as *mut Slab);
+        let slab_ref = &mut *slab.as_ptr();
+        let object = NonNull::new_unchecked(ptr.cast::<FreeObject>());
+        object.as_ptr().write(FreeObject {
+            next: slab_ref.free,
+        });
+
+        if slab_ref.free.is_none() {
+            unlink(&mut self.full, slab);
+            link(&mut self.partial, slab);
+            self.full_slabs -= 1;
+            self.partial_slabs += 1;
+        }
+        slab_ref.free = Some(object);
+        slab_ref.in_use -= 1;
+        self.in_use -= 1;
+        if slab_ref.in_use > 0 {
+            return;
+        }
+
+        self.partial_slabs -= 1;
+        if self.empty_slabs < KEEP_EMPTY_SLABS {
+            self.empty_slabs += 1;
+            return;
+        }
+        unlink(&mut self.partial, slab);
+        heap.deallocate(slab.cast(), self.slab_layout());
+        self.reclaimed += 1;
+    }
+}
+
+fn link(head: &mut Option<NonNull<Slab>>, mut slab: NonNull<Slab>) {
+    unsafe {
+        slab.as_mut().prev = None;
+        slab.as_mut().next = *head;
+        if let Some(mut next) = *head {
+            next.as_mut().prev = Some(slab);
+        }
+    }
+    *head = Some(slab);
+}
+
+fn unlink(head: &mut Option<NonNull<Slab>>, slab: NonNull<Slab>) {
+    unsafe {
+        let Slab { next, prev, .. } = *slab.as_ptr();
+        match prev {
+            Some(mut prev) => prev.as_mut().next = next,
+            None => *head = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+    }
+}
+
+pub struct SlabAllocator {
+    caches: [Option<SlabCache>; MAX_CACHES],
+    // どのキャッシュにも入らない大きなオブジェクトとスラブ自体はここから確保する
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+// スラブはヒープの中にあり、アロケータのロックを通してしか触らない
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        let mut caches = [const { None }; MAX_CACHES];
+        let mut index = 0;
+        while index < SIZE_CLASSES.len() {
+            let (name, size) = SIZE_CLASSES[index];
+            let layout = unsafe { Layout::from_size_align_unchecked(size, size) };
+            caches[index] = Some(SlabCache::new(name, layout, None));
+            index += 1;
+        }
+        Self {
+            caches,
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator
+            .init(heap_start as *mut u8, heap_size);
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// layoutのオブジェクト専用のキャッシュを登録する
+    /// 同じレイアウトのオブジェクトがまだ1つも確保されていないうちに呼ぶ必要がある
+    pub fn register_cache(&mut self, name: &'static str, layout: Layout) {
+        let cache = SlabCache::new(name, layout, Some(layout));
+        // 名前付きキャッシュは大きさごとのキャッシュより先に探すので、前に入れる
+        let slot = self
+            .caches
+            .iter()
+            .position(Option::is_none)
+            .expect("too many slab caches");
+        self.caches[..=slot].rotate_right(1);
+        self.caches[0] = Some(cache);
+    }
+
+    /// 登録されているキャッシュの統計
+    pub fn stats(&self) -> [Option<CacheStats>; MAX_CACHES] {
+        self.caches
+            .each_ref()
+            .map(|cache| cache.as_ref().map(SlabCache::stats))
+    }
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let SlabAllocator {
+            caches,
+            fallback_allocator,
+        } = &mut *allocator;
+        let cache = caches
+            .iter_mut()
+            .flatten()
+            .find(|cache| cache.accepts(&layout));
+        match cache {
+            Some(cache) => cache.alloc(fallback_allocator),
+            None => alloc_from_heap(fallback_allocator, layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        let SlabAllocator {
+            caches,
+            fallback_allocator,
+        } = &mut *allocator;
+        let cache = caches
+            .iter_mut()
+            .flatten()
+            .find(|cache| cache.accepts(&layout));
+        match cache {
+            Some(cache) => cache.dealloc(ptr, fallback_allocator),
+            None => fallback_allocator.deallocate(NonNull::new(ptr).unwrap(), layout),
+        }
+    }
+}
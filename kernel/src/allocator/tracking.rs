@@ -0,0 +1,106 @@
+//! 確保中の領域と、それを確保した場所の記録(alloc-trackingフィーチャ)
+//!
+//! アロケータの中で使うのでヒープは使わず、決まった大きさの表に入れる
+//! 表に入りきらなかった確保は数だけ数える
+
+use core::arch::asm;
+
+pub const MAX_TRACKED: usize = 1024;
+pub const CALLER_DEPTH: usize = 6;
+// GlobalAlloc::allocを呼ぶ__rg_allocのフレームを飛ばす
+const SKIP_FRAMES: usize = 1;
+// これより離れたフレームは別のスタックとみなして辿らない
+const MAX_FRAME_DISTANCE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    // 確保した順に振る番号
+    pub sequence: u64,
+    // 呼び出し元のリターンアドレスを近い順に並べたもの。辿れなかったところは0
+    pub callers: [u64; CALLER_DEPTH],
+}
+
+pub struct Tracker {
+    // アドレスから決めた位置から順に空きを探して入れる
+    entries: [Option<Allocation>; MAX_TRACKED],
+    next_sequence: u64,
+    untracked: usize,
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_TRACKED],
+            next_sequence: 0,
+            untracked: 0,
+        }
+    }
+
+    fn slots(address: usize) -> impl Iterator<Item = usize> {
+        let start = (address >> 3) % MAX_TRACKED;
+        (0..MAX_TRACKED).map(move |offset| (start + offset) % MAX_TRACKED)
+    }
+
+    pub fn record(&mut self, address: usize, size: usize, callers: [u64; CALLER_DEPTH]) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        match Self::slots(address).find(|&slot| self.entries[slot].is_none()) {
+            Some(slot) => {
+                self.entries[slot] = Some(Allocation {
+                    address,
+                    size,
+                    sequence,
+                    callers,
+                })
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    pub fn forget(&mut self, address: usize) {
+        let slot = Self::slots(address).find(
+            |&slot| matches!(self.entries[slot], Some(allocation) if allocation.address == address),
+        );
+        if let Some(slot) = slot {
+            self.entries[slot] = None;
+        }
+    }
+
+    pub fn live(&self) -> impl Iterator<Item = &Allocation> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn untracked(&self) -> usize {
+        self.untracked
+    }
+}
+
+/// rbpのチェーンを辿って、呼び出し元のリターンアドレスを集める
+/// force-frame-pointersでビルドしている必要がある
+#[inline(always)]
+pub fn callers() -> [u64; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    for depth in 0..SKIP_FRAMES + CALLER_DEPTH {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        // [rbp]に呼び出し元のrbp、[rbp + 8]にリターンアドレスがある
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if depth >= SKIP_FRAMES {
+            callers[depth - SKIP_FRAMES] = return_address;
+        }
+        if next <= rbp || next - rbp > MAX_FRAME_DISTANCE {
+            break;
+        }
+        rbp = next;
+    }
+    callers
+}
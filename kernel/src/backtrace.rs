@@ -0,0 +1,222 @@
+//! フレームポインタを辿るバックトレースと、カーネルのシンボル表
+//!
+//! シンボル表はビルド時にbuild.rsがカーネルのELFの.ksymsセクションに書き込む
+//! 形式はbuild.rsのembed_symbolsを参照
+//! カーネルだけを単独でビルドしたときは空のままなので、アドレスだけを表示する
+
+use core::{arch::asm, fmt, hint::black_box};
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, Translate},
+    VirtAddr,
+};
+
+use crate::{
+    memory::{address_space, phys_to_virt, PHYSICAL_MEMORY_OFFSET},
+    println,
+};
+
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+const MAX_DEPTH: usize = 32;
+// これより離れたフレームは別のスタックとみなして辿らない
+const MAX_FRAME_DISTANCE: u64 = 64 * 1024;
+
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+struct SymbolTable {
+    data: &'static [u8],
+    count: usize,
+    // リンク時のアドレスと実際に読み込まれたアドレスの差
+    load_offset: u64,
+}
+
+impl SymbolTable {
+    fn get() -> Option<Self> {
+        // 中身はビルド後に書き込まれるので、コンパイラに0だと決めつけさせない
+        let data = unsafe {
+            core::slice::from_raw_parts(black_box(SYMBOL_TABLE.as_ptr()), SYMBOL_TABLE_SIZE)
+        };
+        if &data[..4] != MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 4) as usize;
+        let link_address = read_u64(data, 8);
+        Some(Self {
+            data,
+            count,
+            load_offset: (data.as_ptr() as u64).wrapping_sub(link_address),
+        })
+    }
+
+    fn entry(&self, index: usize) -> (u64, u64, usize) {
+        let entry = &self.data[HEADER_SIZE + index * ENTRY_SIZE..];
+        (
+            read_u64(entry, 0).wrapping_add(self.load_offset),
+            read_u32(entry, 8) as u64,
+            read_u32(entry, 12) as usize,
+        )
+    }
+
+    fn name(&self, offset: usize) -> &'static str {
+        let names = &self.data[HEADER_SIZE + self.count * ENTRY_SIZE + offset..];
+        let len = names.iter().position(|&b| b == 0).unwrap_or(names.len());
+        core::str::from_utf8(&names[..len]).unwrap_or("?")
+    }
+
+    /// addrを含む関数の名前と、関数の先頭からの距離
+    fn lookup(&self, addr: u64) -> Option<(&'static str, u64)> {
+        // addr以下で最後に始まる関数を二分探索で探す
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.entry(middle).0 <= addr {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let (start, size, name) = self.entry(low.checked_sub(1)?);
+        let offset = addr - start;
+        if size != 0 && offset >= size {
+            return None;
+        }
+        Some((self.name(name), offset))
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// アドレスを、分かれば関数名+オフセットを付けて表示する
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match SymbolTable::get().and_then(|table| table.lookup(self.0)) {
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+// リターンアドレスは呼び出し命令の次を指していて、関数の末尾の呼び出しなら次の関数に
+// はみ出すので、1つ前のバイトで関数を探す
+struct ReturnAddress(u64);
+
+impl fmt::Display for ReturnAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match SymbolTable::get().and_then(|table| table.lookup(self.0 - 1)) {
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset + 1),
+            None => Ok(()),
+        }
+    }
+}
+
+// アドレスが現在のページテーブルでマップされているか
+// physical memory offsetが分からないうちは確かめられないので、マップされているとみなす
+fn is_mapped(addr: u64) -> bool {
+    if PHYSICAL_MEMORY_OFFSET.get().is_none() {
+        return true;
+    }
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    let level_4_table =
+        unsafe { &mut *phys_to_virt(Cr3::read().0.start_address()).as_mut_ptr::<PageTable>() };
+    let mapper = unsafe { address_space::mapper(level_4_table) };
+    mapper.translate_addr(addr).is_some()
+}
+
+/// rbpのチェーンを辿って、リターンアドレスを呼び出し元に向かって順に返す
+/// force-frame-pointersでビルドしている必要がある
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Frames {
+    pub fn new(rbp: u64) -> Self {
+        Self { rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        if self.depth >= MAX_DEPTH
+            || rbp == 0
+            || rbp % 8 != 0
+            || !is_mapped(rbp)
+            || !is_mapped(rbp + 8)
+        {
+            return None;
+        }
+        // [rbp]に呼び出し元のrbp、[rbp + 8]にリターンアドレスがある
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            return None;
+        }
+        self.rbp = if next > rbp && next - rbp <= MAX_FRAME_DISTANCE {
+            next
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+/// 呼び出し元からのバックトレースを表示する
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    println!("backtrace:");
+    print_frames(Frames::new(rbp));
+}
+
+/// 呼び出し元からのリターンアドレスを近い順にframesに入れ、入れた数を返す
+/// 後で表示するために取っておく用で、ヒープは使わない
+#[cfg(feature = "lockdep")]
+#[inline(never)]
+pub fn capture(frames: &mut [u64]) -> usize {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    frames
+        .iter_mut()
+        .zip(Frames::new(rbp))
+        .map(|(frame, return_address)| *frame = return_address)
+        .count()
+}
+
+/// 例外で割り込まれたコードのバックトレースを表示する
+/// 例外用のスタックからは割り込まれたスタックに辿れないので、割り込まれたときのripとrbpから始める
+pub fn print_interrupted(rip: u64, rbp: u64) {
+    println!("backtrace:");
+    println!("  {:>2}: {}", 0, Symbolized(rip));
+    for (depth, return_address) in Frames::new(rbp).enumerate() {
+        println!("  {:>2}: {}", depth + 1, ReturnAddress(return_address));
+    }
+}
+
+/// リターンアドレスの並びを、分かれば関数名を付けて表示する
+pub fn print_frames(frames: impl IntoIterator<Item = u64>) {
+    for (depth, return_address) in frames.into_iter().enumerate() {
+        println!("  {:>2}: {}", depth, ReturnAddress(return_address));
+    }
+}
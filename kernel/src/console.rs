@@ -2,9 +2,10 @@ use bootloader_api::info::{FrameBufferInfo, PixelFormat};
 use conquer_once::spin::OnceCell;
 use core::{fmt, ptr};
 use noto_sans_mono_bitmap::{get_raster, RasterizedChar};
-use spinning_top::Spinlock;
 
-pub static CONSOLE: OnceCell<Spinlock<Console>> = OnceCell::uninit();
+use crate::sync::irq_spinlock::IrqSpinlock;
+
+pub static CONSOLE: OnceCell<IrqSpinlock<Console>> = OnceCell::uninit();
 
 #[macro_export]
 macro_rules! print {
@@ -15,6 +16,7 @@ macro_rules! print {
 
 #[macro_export]
 macro_rules! println {
+    () => ($crate::print!("\n"));
     ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(
         concat!($fmt, "\n"), $($arg)*));
@@ -54,6 +56,8 @@ pub struct Console {
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    // 入力位置に下線のカーソルを表示するか
+    cursor_visible: bool,
 }
 
 impl Console {
@@ -63,6 +67,7 @@ impl Console {
             info,
             x_pos: 0,
             y_pos: 0,
+            cursor_visible: false,
         };
         console.clear();
         console
@@ -95,9 +100,56 @@ impl Console {
         self.x_pos = BORDER_PADDING;
     }
 
+    // 1行に書ける文字数
+    fn columns(&self) -> usize {
+        (self.width() - BORDER_PADDING - 1) / font_constants::CHAR_RASTER_WIDTH
+    }
+
+    // 1文字左に戻る。行の先頭では前の行の末尾に戻る
+    fn move_left(&mut self) {
+        if self.x_pos >= BORDER_PADDING + font_constants::CHAR_RASTER_WIDTH {
+            self.x_pos -= font_constants::CHAR_RASTER_WIDTH;
+        } else if self.y_pos > BORDER_PADDING {
+            self.y_pos -= font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+            self.x_pos = BORDER_PADDING + (self.columns() - 1) * font_constants::CHAR_RASTER_WIDTH;
+        }
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.draw_cursor(false);
+        self.cursor_visible = visible;
+        self.draw_cursor(visible);
+    }
+
+    // 現在の位置の文字の下に線を引く(消す)
+    fn draw_cursor(&mut self, visible: bool) {
+        let char_height = font_constants::CHAR_RASTER_HEIGHT.val();
+        if self.x_pos + font_constants::CHAR_RASTER_WIDTH >= self.width()
+            || self.y_pos + char_height >= self.height()
+        {
+            return;
+        }
+        let intensity = if visible { 0xff } else { 0 };
+        for y in self.y_pos + char_height - 2..self.y_pos + char_height {
+            for x in self.x_pos..self.x_pos + font_constants::CHAR_RASTER_WIDTH {
+                self.write_pixel(x, y, intensity);
+            }
+        }
+    }
+
     fn write_char(&mut self, c: char) {
+        self.draw_cursor(false);
+        self.write_char_at_cursor(c);
+        if self.cursor_visible {
+            self.draw_cursor(true);
+        }
+    }
+
+    fn write_char_at_cursor(&mut self, c: char) {
         match c {
             '\n' => self.newline(),
+            // バックスペースは消さずに1文字戻るだけ
+            '\x08' => self.move_left(),
             c => {
                 let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
                 if new_xpos >= self.width() {
@@ -0,0 +1,277 @@
+//! ELF64の実行ファイルのパーサ
+//! x86_64のリトルエンディアンの実行ファイルだけを扱う
+
+use core::ops::Range;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const RELA_SIZE: usize = 24;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const R_X86_64_RELATIVE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    InvalidMagic,
+    UnsupportedClass,
+    UnsupportedMachine,
+    UnsupportedType(u16),
+    UnsupportedRelocation(u32),
+    Truncated,
+    // ヘッダのオフセットや大きさを足すと溢れる
+    InvalidHeader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    Executable,
+    // 位置独立実行ファイル(static-pie)
+    SharedObject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SegmentType {
+    Load = 1,
+    Dynamic = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl ProgramHeader {
+    pub const FLAG_EXECUTE: u32 = 1;
+    pub const FLAG_WRITE: u32 = 2;
+
+    pub fn is(&self, segment_type: SegmentType) -> bool {
+        self.p_type == segment_type as u32
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & Self::FLAG_WRITE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & Self::FLAG_EXECUTE != 0
+    }
+
+    // parseで溢れないことを確認済み
+    pub fn file_range(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.filesz) as usize
+    }
+
+    // ファイル上の範囲と仮想アドレスの範囲の終わりが溢れないか
+    fn is_valid(&self) -> bool {
+        self.offset.checked_add(self.filesz).is_some()
+            && self
+                .vaddr
+                .checked_add(self.memsz.max(self.filesz))
+                .is_some()
+    }
+}
+
+/// R_X86_64_RELATIVEの再配置: ロードしたアドレス+offsetにロードしたアドレス+addendを書く
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation {
+    pub offset: u64,
+    pub addend: u64,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    elf_type: ElfType,
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+        let elf_type = match read_u16(data, 16)? {
+            2 => ElfType::Executable,
+            3 => ElfType::SharedObject,
+            other => return Err(ElfError::UnsupportedType(other)),
+        };
+
+        let elf = Self {
+            data,
+            elf_type,
+            entry: read_u64(data, 24)?,
+            phoff: read_u64(data, 32)?,
+            phnum: read_u16(data, 56)?,
+        };
+        // プログラムヘッダがすべてファイルに収まっているか先に確かめておく
+        let phend = (elf.phnum as usize)
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| usize::try_from(elf.phoff).ok()?.checked_add(size))
+            .ok_or(ElfError::InvalidHeader)?;
+        if phend > data.len() {
+            return Err(ElfError::Truncated);
+        }
+        for header in elf.program_headers() {
+            if !header.is_valid() {
+                return Err(ElfError::InvalidHeader);
+            }
+            if header.is(SegmentType::Load) && header.file_range().end > data.len() {
+                return Err(ElfError::Truncated);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn elf_type(&self) -> ElfType {
+        self.elf_type
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn phoff(&self) -> u64 {
+        self.phoff
+    }
+
+    pub fn phnum(&self) -> u16 {
+        self.phnum
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum as usize).map(move |i| {
+            let base = self.phoff as usize + i * PROGRAM_HEADER_SIZE;
+            let data = self.data;
+            // parseで範囲は確認済み
+            ProgramHeader {
+                p_type: read_u32(data, base).unwrap(),
+                flags: read_u32(data, base + 4).unwrap(),
+                offset: read_u64(data, base + 8).unwrap(),
+                vaddr: read_u64(data, base + 16).unwrap(),
+                filesz: read_u64(data, base + 32).unwrap(),
+                memsz: read_u64(data, base + 40).unwrap(),
+            }
+        })
+    }
+
+    /// PT_DYNAMICに書かれたRELAの再配置を列挙する
+    pub fn relocations(&self) -> Result<impl Iterator<Item = Relocation> + '_, ElfError> {
+        let mut rela = None;
+        let mut rela_size = 0;
+        if let Some(dynamic) = self
+            .program_headers()
+            .find(|header| header.is(SegmentType::Dynamic))
+        {
+            let dynamic = self
+                .data
+                .get(dynamic.file_range())
+                .ok_or(ElfError::Truncated)?;
+            for entry in dynamic.chunks_exact(16) {
+                let tag = read_u64(entry, 0)?;
+                let value = read_u64(entry, 8)?;
+                match tag {
+                    DT_NULL => break,
+                    DT_RELA => rela = Some(value),
+                    DT_RELASZ => rela_size = value as usize,
+                    _ => {}
+                }
+            }
+        }
+
+        // DT_RELAは仮想アドレスなので、ファイル上のオフセットに直す
+        let entries = match rela {
+            Some(vaddr) => {
+                let offset = self.vaddr_to_offset(vaddr).ok_or(ElfError::Truncated)?;
+                let end = offset
+                    .checked_add(rela_size)
+                    .ok_or(ElfError::InvalidHeader)?;
+                self.data.get(offset..end).ok_or(ElfError::Truncated)?
+            }
+            None => &[],
+        };
+
+        for entry in entries.chunks_exact(RELA_SIZE) {
+            let relocation_type = read_u64(entry, 8)? as u32;
+            if relocation_type != R_X86_64_RELATIVE {
+                return Err(ElfError::UnsupportedRelocation(relocation_type));
+            }
+        }
+        Ok(entries.chunks_exact(RELA_SIZE).map(|entry| Relocation {
+            offset: read_u64(entry, 0).unwrap(),
+            addend: read_u64(entry, 16).unwrap(),
+        }))
+    }
+
+    /// 仮想アドレスをファイル上のオフセットに変換する
+    /// 各ヘッダの範囲の終わりはparseで溢れないことを確認済み
+    pub fn vaddr_to_offset(&self, vaddr: u64) -> Option<usize> {
+        self.program_headers()
+            .find(|header| {
+                header.is(SegmentType::Load)
+                    && (header.vaddr..header.vaddr + header.filesz).contains(&vaddr)
+            })
+            .map(|header| (vaddr - header.vaddr + header.offset) as usize)
+    }
+
+    /// ファイル上のオフセットを仮想アドレスに変換する
+    /// 各ヘッダの範囲の終わりはparseで溢れないことを確認済み
+    pub fn offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        self.program_headers()
+            .find(|header| {
+                header.is(SegmentType::Load)
+                    && (header.offset..header.offset + header.filesz).contains(&offset)
+            })
+            .map(|header| offset - header.offset + header.vaddr)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data
+        .get(offset..)
+        .and_then(|rest| rest.get(..2))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data
+        .get(offset..)
+        .and_then(|rest| rest.get(..4))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data
+        .get(offset..)
+        .and_then(|rest| rest.get(..8))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
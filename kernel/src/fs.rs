@@ -0,0 +1,122 @@
+//! ファイルシステム
+
+pub mod cpio;
+pub mod devfs;
+pub mod file;
+pub mod ramfs;
+pub mod tmpfs;
+pub mod vfs;
+
+use alloc::{string::String, sync::Arc};
+
+use cpio::CpioError;
+use devfs::DevFs;
+use file::{OpenFile, OpenFlags};
+use ramfs::RamFs;
+use tmpfs::TmpFs;
+use vfs::FileType;
+
+use crate::{print, println, task::shell};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    ReadOnly,
+    InvalidArgument,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    // 今は読めるデータが無い
+    WouldBlock,
+    NoSpace,
+    DirectoryNotEmpty,
+    // 別のファイルシステムへのrename
+    CrossDevice,
+    // マウントポイントなので操作できない
+    Busy,
+}
+
+/// パスを"/"で区切った要素に分ける
+/// 空の要素と"."は読み飛ばす
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+// /tmpに書き込めるデータの上限
+pub const TMPFS_CAPACITY: usize = 32 * 1024;
+
+/// ramdiskをルートに、devfsを/devに、tmpfsを/tmpにマウントする
+pub fn init(ramdisk: &'static [u8]) -> Result<(), CpioError> {
+    let ramfs = RamFs::from_cpio(ramdisk)?;
+    vfs::mount("/", Arc::new(ramfs)).expect("root is already mounted");
+    vfs::mount("/dev", Arc::new(DevFs::new())).expect("/dev is already mounted");
+    vfs::mount("/tmp", Arc::new(TmpFs::new(TMPFS_CAPACITY))).expect("/tmp is already mounted");
+    Ok(())
+}
+
+pub fn register_commands() {
+    shell::register("ls", "list directory contents", ls);
+    shell::register("cat", "print file contents", cat);
+    shell::register("mounts", "list mounted file systems", mounts);
+}
+
+fn ls(args: &[&str]) {
+    let path = args.get(1).copied().unwrap_or("/");
+    let result = vfs::resolve(path).and_then(|dentry| {
+        let inode = dentry.inode();
+        if inode.metadata().file_type != FileType::Directory {
+            println!("{}", dentry.path());
+            return Ok(());
+        }
+        for entry in inode.read_dir()? {
+            let child = inode.lookup(&entry.name)?.metadata();
+            match entry.file_type {
+                FileType::Directory => println!("{:>8}  {}/", "-", entry.name),
+                _ => println!("{:>8}  {}", child.size, entry.name),
+            }
+        }
+        Ok(())
+    });
+    if let Err(error) = result {
+        println!("ls: {}: {:?}", path, error);
+    }
+}
+
+fn cat(args: &[&str]) {
+    for path in &args[1..] {
+        let result = OpenFile::open(path, OpenFlags::READ_ONLY).and_then(|file| {
+            let mut buf = [0; 256];
+            // UTF-8の文字がバッファの境目で切れても壊れないように、まとめてから表示する
+            let mut contents = alloc::vec::Vec::new();
+            loop {
+                match file.read(&mut buf)? {
+                    0 => break,
+                    len => contents.extend_from_slice(&buf[..len]),
+                }
+            }
+            print!("{}", String::from_utf8_lossy(&contents));
+            Ok(())
+        });
+        if let Err(error) = result {
+            println!("cat: {}: {:?}", path, error);
+        }
+    }
+}
+
+fn mounts(_args: &[&str]) {
+    for mount in vfs::mounts() {
+        match mount.usage {
+            Some((used, capacity)) => println!(
+                "{:<8} {:<8} {} / {} KiB",
+                mount.path,
+                mount.name,
+                used / 1024,
+                capacity / 1024
+            ),
+            None => println!("{:<8} {}", mount.path, mount.name),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! newc形式のcpioアーカイブの読み込み
+
+const MAGIC: &[u8; 6] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    InvalidMagic,
+    InvalidHeader,
+    InvalidName,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Directory,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn entry_type(&self) -> EntryType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => EntryType::File,
+            MODE_DIRECTORY => EntryType::Directory,
+            _ => EntryType::Other,
+        }
+    }
+}
+
+/// アーカイブのエントリを先頭から順に返す
+/// TRAILER!!!に着いたところで終わる
+pub struct Archive<'a> {
+    data: &'a [u8],
+    offset: usize,
+    finished: bool,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            finished: false,
+        }
+    }
+
+    fn read_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC {
+            return Err(CpioError::InvalidMagic);
+        }
+        let field = |index: usize| parse_hex(&header[6 + index * 8..6 + (index + 1) * 8]);
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self
+            .data
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        // 名前はNUL終端されている
+        let name = match name.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| CpioError::InvalidName)?,
+            _ => return Err(CpioError::InvalidName),
+        };
+
+        let data_start = align_up(name_start + name_size);
+        let data = self
+            .data
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.offset = align_up(data_start + file_size);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(error) => {
+                self.finished = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+fn parse_hex(bytes: &[u8]) -> Result<u32, CpioError> {
+    let text = core::str::from_utf8(bytes).map_err(|_| CpioError::InvalidHeader)?;
+    u32::from_str_radix(text, 16).map_err(|_| CpioError::InvalidHeader)
+}
+
+// ヘッダ+名前とデータはそれぞれ4バイト境界に揃えられている
+fn align_up(offset: usize) -> usize {
+    (offset + 3) & !3
+}
@@ -0,0 +1,184 @@
+//! デバイスをファイルとして見せるファイルシステム
+//!
+//! /dev/console  書き込むと画面に出力する
+//! /dev/keyboard キーボードから入力された文字をUTF-8で読み出す
+//! /dev/null     書き込みを捨て、読むと常にEOF
+
+use alloc::{collections::btree_map::BTreeMap, string::ToString, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+use super::{
+    vfs::{DirEntry, FileSystem, FileType, Inode, Metadata},
+    FsError,
+};
+use crate::console;
+
+const KEYBOARD_BUFFER_SIZE: usize = 256;
+
+static KEYBOARD_BUFFER: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// キーボード割り込みから呼ばれ、デコードした文字を/dev/keyboardのバッファに入れる
+/// 誰も読まずにバッファが一杯になったら古い入力は残したまま捨てる
+pub fn push_scancode(scancode: u8) {
+    lazy_static! {
+        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+            Mutex::new(Keyboard::new(
+                ScancodeSet1::new(),
+                layouts::Us104Key,
+                HandleControl::Ignore
+            ));
+    }
+
+    let Ok(buffer) = KEYBOARD_BUFFER.try_get() else {
+        return;
+    };
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+            let mut bytes = [0; 4];
+            for byte in character.encode_utf8(&mut bytes).bytes() {
+                let _ = buffer.push(byte);
+            }
+        }
+    }
+}
+
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        // UTF-8として途中までしか解釈できないときは、そこまでを書き込んだことにする
+        let text = match core::str::from_utf8(buf) {
+            Ok(text) => text,
+            Err(error) if error.valid_up_to() > 0 => {
+                core::str::from_utf8(&buf[..error.valid_up_to()]).unwrap()
+            }
+            Err(_) => return Err(FsError::InvalidArgument),
+        };
+        console::_print(format_args!("{}", text));
+        Ok(text.len())
+    }
+}
+
+struct KeyboardDevice;
+
+impl Inode for KeyboardDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let buffer = KEYBOARD_BUFFER.try_get().map_err(|_| FsError::WouldBlock)?;
+        let mut read = 0;
+        while read < buf.len() {
+            let Some(byte) = buffer.pop() else {
+                break;
+            };
+            buf[read] = byte;
+            read += 1;
+        }
+        if read == 0 && !buf.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+        Ok(read)
+    }
+}
+
+struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+struct DeviceDirectory {
+    devices: BTreeMap<&'static str, Arc<dyn Inode>>,
+}
+
+impl Inode for DeviceDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: self.devices.len() as u64,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.devices.get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|(name, device)| DirEntry {
+                name: name.to_string(),
+                file_type: device.metadata().file_type,
+            })
+            .collect())
+    }
+}
+
+pub struct DevFs {
+    root: Arc<DeviceDirectory>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        KEYBOARD_BUFFER.init_once(|| ArrayQueue::new(KEYBOARD_BUFFER_SIZE));
+
+        let mut devices: BTreeMap<&'static str, Arc<dyn Inode>> = BTreeMap::new();
+        devices.insert("console", Arc::new(Console));
+        devices.insert("keyboard", Arc::new(KeyboardDevice));
+        devices.insert("null", Arc::new(Null));
+        Self {
+            root: Arc::new(DeviceDirectory { devices }),
+        }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
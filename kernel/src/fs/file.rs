@@ -0,0 +1,208 @@
+//! オープンしたファイルとプロセスごとのファイルディスクリプタテーブル
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{
+    vfs::{self, Dentry, DirEntry, FileType, Metadata},
+    FsError,
+};
+use crate::sync::mutex::Mutex;
+
+/// openに渡すフラグ
+/// 値はLinuxのO_RDONLYなどに合わせる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u64);
+
+impl OpenFlags {
+    pub const READ_ONLY: Self = Self(0);
+    pub const WRITE_ONLY: Self = Self(1);
+    pub const READ_WRITE: Self = Self(2);
+    // 無ければ作る
+    pub const CREATE: Self = Self(0o100);
+    // 開いたときに中身を空にする
+    pub const TRUNCATE: Self = Self(0o1000);
+    // 書き込みは常に末尾に追加する
+    pub const APPEND: Self = Self(0o2000);
+    const ACCESS_MODE_MASK: u64 = 3;
+
+    pub fn from_bits(bits: u64) -> Result<Self, FsError> {
+        if bits & Self::ACCESS_MODE_MASK == Self::ACCESS_MODE_MASK {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Self(bits))
+    }
+
+    pub fn readable(self) -> bool {
+        self.0 & Self::ACCESS_MODE_MASK != Self::WRITE_ONLY.0
+    }
+
+    pub fn writable(self) -> bool {
+        self.0 & Self::ACCESS_MODE_MASK != Self::READ_ONLY.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub struct OpenFile {
+    dentry: Dentry,
+    flags: OpenFlags,
+    // ファイルの読み書き位置。ディレクトリでは次に返すエントリの番号
+    // forkした親子で共有し、読み書きの間ロックを持ち続けるので、待つ側は眠らせる
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<Self>, FsError> {
+        let dentry = match vfs::resolve(path) {
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                vfs::create(path, FileType::File)?
+            }
+            result => result?,
+        };
+        if flags.writable() && dentry.inode().metadata().file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if flags.contains(OpenFlags::TRUNCATE) && flags.writable() {
+            dentry.inode().truncate(0)?;
+        }
+        Ok(Arc::new(Self {
+            dentry,
+            flags,
+            offset: Mutex::new(0),
+        }))
+    }
+
+    pub fn dentry(&self) -> &Dentry {
+        &self.dentry
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.readable() {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        let read = self.dentry.inode().read_at(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.writable() {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.stat().size;
+        }
+        let written = self.dentry.inode().write_at(*offset, buf)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match position {
+            SeekFrom::Start(start) => (start, 0),
+            SeekFrom::Current(delta) => (*offset, delta),
+            SeekFrom::End(delta) => (self.stat().size, delta),
+        };
+        *offset = base
+            .checked_add_signed(delta)
+            .ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    pub fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if !self.flags.writable() {
+            return Err(FsError::BadFileDescriptor);
+        }
+        self.dentry.inode().truncate(size)
+    }
+
+    pub fn stat(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    /// 次のディレクトリエントリを返す。最後まで読んだらNone
+    pub fn read_dir_next(&self) -> Result<Option<DirEntry>, FsError> {
+        let mut offset = self.offset.lock();
+        let entry = self
+            .dentry
+            .inode()
+            .read_dir()?
+            .into_iter()
+            .nth(*offset as usize);
+        if entry.is_some() {
+            *offset += 1;
+        }
+        Ok(entry)
+    }
+}
+
+/// プロセスごとのファイルディスクリプタテーブル
+/// 空いている一番小さい番号から割り当てる
+/// forkした子は開いているファイルを親と共有する
+#[derive(Default, Clone)]
+pub struct FileDescriptorTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FileDescriptorTable {
+    pub const MAX_FILES: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 標準入力、標準出力、標準エラー出力をコンソールにつないだテーブル
+    pub fn with_stdio() -> Result<Self, FsError> {
+        let mut table = Self::new();
+        table.insert(OpenFile::open("/dev/keyboard", OpenFlags::READ_ONLY)?)?;
+        table.insert(OpenFile::open("/dev/console", OpenFlags::WRITE_ONLY)?)?;
+        table.insert(OpenFile::open("/dev/console", OpenFlags::WRITE_ONLY)?)?;
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> Result<usize, FsError> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= Self::MAX_FILES {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<OpenFile>, FsError> {
+        self.files
+            .get(fd)
+            .and_then(Option::clone)
+            .ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Result<Arc<OpenFile>, FsError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FsError::BadFileDescriptor)
+    }
+}
@@ -0,0 +1,149 @@
+//! ramdiskのcpioアーカイブから作る読み込み専用のファイルシステム
+//! ファイルの中身はコピーせず、ramdiskのメモリをそのまま参照する
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{
+    components,
+    cpio::{Archive, CpioError, EntryType},
+    vfs::{DirEntry, FileSystem, FileType, Inode, Metadata},
+    FsError,
+};
+
+pub enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<Node>>),
+}
+
+impl Node {
+    fn new_directory() -> Self {
+        Node::Directory(BTreeMap::new())
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::File,
+            Node::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let size = match self {
+            Node::File(data) => data.len(),
+            Node::Directory(children) => children.len(),
+        };
+        Metadata {
+            file_type: self.file_type(),
+            size: size as u64,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match self {
+            Node::Directory(children) => children
+                .get(name)
+                .map(|node| node.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        match self {
+            Node::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    file_type: node.file_type(),
+                })
+                .collect()),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Node::File(data) = self else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn mapped_data(&self) -> Option<&'static [u8]> {
+        match self {
+            Node::File(data) => Some(data),
+            Node::Directory(_) => None,
+        }
+    }
+}
+
+pub struct RamFs {
+    root: Arc<Node>,
+}
+
+impl RamFs {
+    pub fn from_cpio(archive: &'static [u8]) -> Result<Self, CpioError> {
+        let mut root = Node::new_directory();
+        for entry in Archive::new(archive) {
+            let entry = entry?;
+            let node = match entry.entry_type() {
+                EntryType::File => Node::File(entry.data),
+                EntryType::Directory => Node::new_directory(),
+                // シンボリックリンクやデバイスファイルは扱わない
+                EntryType::Other => continue,
+            };
+            insert(&mut root, entry.name, node)?;
+        }
+        Ok(Self {
+            root: Arc::new(root),
+        })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// 途中のディレクトリがアーカイブに無くても作っておく
+// 組み立てている間は他からの参照が無いので、Arc::get_mutで書き換えられる
+fn insert(root: &mut Node, path: &str, node: Node) -> Result<(), CpioError> {
+    let mut names = components(path).peekable();
+    let mut current = root;
+    while let Some(name) = names.next() {
+        let Node::Directory(children) = current else {
+            return Err(CpioError::InvalidName);
+        };
+        if names.peek().is_none() {
+            // ディレクトリのエントリが中身より後に来ても中身を消さない
+            let is_directory = matches!(node, Node::Directory(_));
+            let exists = children
+                .get(name)
+                .is_some_and(|child| matches!(**child, Node::Directory(_)));
+            if !(is_directory && exists) {
+                children.insert(name.to_string(), Arc::new(node));
+            }
+            return Ok(());
+        }
+        let child = children
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Node::new_directory()));
+        current = Arc::get_mut(child).ok_or(CpioError::InvalidName)?;
+    }
+    // "."などルート自身のエントリ
+    Ok(())
+}
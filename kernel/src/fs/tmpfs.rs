@@ -0,0 +1,310 @@
+//! ヒープ上に中身を置く書き込み可能なファイルシステム
+//!
+//! ファイルの中身はPAGE_SIZEごとのページに分けて確保し、確保したページの合計が
+//! 作成時に決めた上限を超えないようにする
+
+use alloc::{
+    boxed::Box,
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{
+    vfs::{DirEntry, FileSystem, FileType, Inode, Metadata},
+    FsError,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+// ファイルシステム全体で確保したページの大きさ
+struct Usage {
+    used: AtomicUsize,
+    capacity: usize,
+}
+
+impl Usage {
+    // ファイルの大きさが上限に収まるか確かめてusizeにする
+    // ページを足す前に、大きすぎる値をはじいておく
+    fn check_size(&self, size: u64) -> Result<usize, FsError> {
+        usize::try_from(size)
+            .ok()
+            .filter(|&size| size <= self.capacity)
+            .ok_or(FsError::NoSpace)
+    }
+
+    fn reserve(&self, pages: usize) -> Result<(), FsError> {
+        let size = pages.checked_mul(PAGE_SIZE).ok_or(FsError::NoSpace)?;
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(size)
+                    .filter(|&total| total <= self.capacity)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoSpace)
+    }
+
+    fn release(&self, pages: usize) {
+        self.used.fetch_sub(pages * PAGE_SIZE, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct FileData {
+    pages: Vec<Box<[u8]>>,
+    size: usize,
+}
+
+enum NodeKind {
+    File(Mutex<FileData>),
+    Directory(Mutex<BTreeMap<String, Arc<Node>>>),
+}
+
+pub struct Node {
+    usage: Arc<Usage>,
+    kind: NodeKind,
+}
+
+impl Node {
+    fn new(usage: Arc<Usage>, file_type: FileType) -> Result<Self, FsError> {
+        let kind = match file_type {
+            FileType::File => NodeKind::File(Mutex::new(FileData::default())),
+            FileType::Directory => NodeKind::Directory(Mutex::new(BTreeMap::new())),
+            FileType::CharDevice => return Err(FsError::InvalidArgument),
+        };
+        Ok(Self { usage, kind })
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            NodeKind::File(_) => FileType::File,
+            NodeKind::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn children(&self) -> Result<&Mutex<BTreeMap<String, Arc<Node>>>, FsError> {
+        match &self.kind {
+            NodeKind::Directory(children) => Ok(children),
+            NodeKind::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn file(&self) -> Result<&Mutex<FileData>, FsError> {
+        match &self.kind {
+            NodeKind::File(data) => Ok(data),
+            NodeKind::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&self.kind, NodeKind::Directory(children) if children.lock().is_empty())
+    }
+
+    // sizeまで書き込めるようにページを足す
+    fn grow(&self, data: &mut FileData, size: usize) -> Result<(), FsError> {
+        let needed = size.div_ceil(PAGE_SIZE).saturating_sub(data.pages.len());
+        if needed == 0 {
+            return Ok(());
+        }
+        self.usage.reserve(needed)?;
+        for _ in 0..needed {
+            data.pages.push(vec![0; PAGE_SIZE].into_boxed_slice());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Node {
+    // 削除されたファイルのページは、最後に閉じられたときに返す
+    fn drop(&mut self) {
+        if let NodeKind::File(data) = &mut self.kind {
+            self.usage.release(data.get_mut().pages.len());
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let size = match &self.kind {
+            NodeKind::File(data) => data.lock().size,
+            NodeKind::Directory(children) => children.lock().len(),
+        };
+        Metadata {
+            file_type: self.file_type(),
+            size: size as u64,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.children()?
+            .lock()
+            .get(name)
+            .map(|node| node.clone() as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .children()?
+            .lock()
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                file_type: node.file_type(),
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.file()?.lock();
+        let start = (offset as usize).min(data.size);
+        let end = start + buf.len().min(data.size - start);
+        let mut position = start;
+        while position < end {
+            let page = &data.pages[position / PAGE_SIZE];
+            let page_offset = position % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - position);
+            buf[position - start..position - start + len]
+                .copy_from_slice(&page[page_offset..page_offset + len]);
+            position += len;
+        }
+        Ok(end - start)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        let end = self.usage.check_size(end)?;
+        let start = offset as usize;
+        let mut data = self.file()?.lock();
+        self.grow(&mut data, end)?;
+        let mut position = start;
+        while position < end {
+            let page_offset = position % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - position);
+            data.pages[position / PAGE_SIZE][page_offset..page_offset + len]
+                .copy_from_slice(&buf[position - start..position - start + len]);
+            position += len;
+        }
+        data.size = data.size.max(end);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let size = self.usage.check_size(size)?;
+        let mut data = self.file()?.lock();
+        if size > data.size {
+            self.grow(&mut data, size)?;
+        } else {
+            let pages = size.div_ceil(PAGE_SIZE);
+            self.usage.release(data.pages.len() - pages);
+            data.pages.truncate(pages);
+            // 後で伸ばしたときに古い中身が見えないように、残したページの末尾を消す
+            if size % PAGE_SIZE != 0 {
+                data.pages[pages - 1][size % PAGE_SIZE..].fill(0);
+            }
+        }
+        data.size = size;
+        Ok(())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut children = self.children()?.lock();
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = Arc::new(Node::new(self.usage.clone(), file_type)?);
+        children.insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut children = self.children()?.lock();
+        let node = children.get(name).ok_or(FsError::NotFound)?;
+        if node.file_type() == FileType::Directory && !node.is_empty_directory() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        children.remove(name);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &dyn Inode,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<Node>()
+            .ok_or(FsError::CrossDevice)?;
+
+        let new_children = new_parent.children()?;
+
+        // 2つのディレクトリのロックを同時に持たないように、先に取り出してから入れる
+        let node = self
+            .children()?
+            .lock()
+            .remove(old_name)
+            .ok_or(FsError::NotFound)?;
+        let mut new_children = new_children.lock();
+        if let Some(existing) = new_children.get(new_name) {
+            // 置き換えられるのはファイル同士か、空のディレクトリへの移動だけ
+            let result = match (node.file_type(), existing.file_type()) {
+                (FileType::Directory, FileType::Directory) if !existing.is_empty_directory() => {
+                    Err(FsError::DirectoryNotEmpty)
+                }
+                (FileType::Directory, FileType::Directory) => Ok(()),
+                (FileType::Directory, _) => Err(FsError::NotADirectory),
+                (_, FileType::Directory) => Err(FsError::IsADirectory),
+                _ => Ok(()),
+            };
+            if let Err(error) = result {
+                drop(new_children);
+                self.children()?.lock().insert(old_name.to_string(), node);
+                return Err(error);
+            }
+        }
+        new_children.insert(new_name.to_string(), node);
+        Ok(())
+    }
+}
+
+pub struct TmpFs {
+    root: Arc<Node>,
+    usage: Arc<Usage>,
+}
+
+impl TmpFs {
+    /// 中身の合計がcapacityバイトまでのtmpfsを作る
+    pub fn new(capacity: usize) -> Self {
+        let usage = Arc::new(Usage {
+            used: AtomicUsize::new(0),
+            capacity,
+        });
+        let root = Node::new(usage.clone(), FileType::Directory).unwrap();
+        Self {
+            root: Arc::new(root),
+            usage,
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn usage(&self) -> Option<(usize, usize)> {
+        Some((self.usage.used.load(Ordering::Relaxed), self.usage.capacity))
+    }
+}
@@ -0,0 +1,284 @@
+//! 仮想ファイルシステム
+//!
+//! ファイルシステムはInodeの木としてVFSに見せる
+//! マウントポイントごとにファイルシステムを登録し、パスは一番長く一致したマウントポイントから辿る
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::any::Any;
+
+use super::{components, FsError};
+use crate::sync::rwlock::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// 同じファイルシステムのInodeへダウンキャストするために使う
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// ファイルやディレクトリの実体
+/// 対応していない操作はデフォルト実装のエラーを返す
+pub trait Inode: AsAny + Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// ファイルの大きさを変える。伸ばした部分は0で埋める
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// ディレクトリにnameという名前のファイルかディレクトリを作る
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// ディレクトリからファイルか空のディレクトリを取り除く
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// このディレクトリのold_nameを、同じファイルシステムのnew_parentのnew_nameに移す
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// 中身がメモリ上にそのまま置かれているファイルならそれを返す
+    /// 大きな実行ファイルをヒープにコピーせずにロードするために使う
+    fn mapped_data(&self) -> Option<&'static [u8]> {
+        None
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// 使っているバイト数と上限。上限の無いファイルシステムはNone
+    fn usage(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+pub struct MountInfo {
+    pub path: String,
+    pub name: &'static str,
+    pub usage: Option<(usize, usize)>,
+}
+
+/// パスを解決した結果
+/// 正規化した絶対パスと、それが指すInodeを持つ
+#[derive(Clone)]
+pub struct Dentry {
+    path: String,
+    // このパスを含むマウントポイント
+    mount_path: String,
+    inode: Arc<dyn Inode>,
+}
+
+impl Dentry {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+}
+
+struct Mount {
+    path: String,
+    file_system: Arc<dyn FileSystem>,
+}
+
+// パスの長い順に並べておき、先に見つかったものを使う
+// パスを辿るたびに読むので、マウントするときだけ書き込みでロックする
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// pathにファイルシステムをマウントする
+/// ルート以外のマウントポイントは、すでにあるディレクトリでなくてもよい
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path.len() < path.len())
+        .unwrap_or(mounts.len());
+    mounts.insert(index, Mount { path, file_system });
+    Ok(())
+}
+
+/// マウントしているファイルシステムをパスの順に返す
+pub fn mounts() -> Vec<MountInfo> {
+    let mut mounts: Vec<_> = MOUNTS
+        .read()
+        .iter()
+        .map(|mount| MountInfo {
+            path: mount.path.clone(),
+            name: mount.file_system.name(),
+            usage: mount.file_system.usage(),
+        })
+        .collect();
+    mounts.sort_by(|a, b| a.path.cmp(&b.path));
+    mounts
+}
+
+/// 絶対パスをInodeまで辿る
+pub fn resolve(path: &str) -> Result<Dentry, FsError> {
+    let path = normalize(path)?;
+    let (mount_path, root) = {
+        let mounts = MOUNTS.read();
+        let mount = mounts
+            .iter()
+            .find(|mount| is_under(&path, &mount.path))
+            .ok_or(FsError::NotFound)?;
+        (mount.path.clone(), mount.file_system.root())
+    };
+
+    let mut inode = root;
+    for name in components(&path[mount_path.len()..]) {
+        inode = inode.lookup(name)?;
+    }
+    Ok(Dentry {
+        path,
+        mount_path,
+        inode,
+    })
+}
+
+/// ファイルかディレクトリを作る
+pub fn create(path: &str, file_type: FileType) -> Result<Dentry, FsError> {
+    let (parent, name) = split_parent(path)?;
+    let parent = resolve(&parent)?;
+    let inode = parent.inode.create(&name, file_type)?;
+    Ok(Dentry {
+        path: normalize(path)?,
+        mount_path: parent.mount_path,
+        inode,
+    })
+}
+
+/// ファイルか空のディレクトリを削除する
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = split_parent(path)?;
+    let dentry = resolve(path)?;
+    if dentry.path == dentry.mount_path {
+        // マウントポイントは消せない
+        return Err(FsError::Busy);
+    }
+    resolve(&parent)?.inode.unlink(&name)
+}
+
+/// 同じファイルシステムの中でファイルかディレクトリを移動する
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = split_parent(old_path)?;
+    let (new_parent, new_name) = split_parent(new_path)?;
+    let old = resolve(old_path)?;
+    let new_parent = resolve(&new_parent)?;
+    if old.path == old.mount_path {
+        return Err(FsError::Busy);
+    }
+    if old.mount_path != new_parent.mount_path {
+        return Err(FsError::CrossDevice);
+    }
+    // ディレクトリを自分の下に移すとどこからも辿れなくなる
+    if is_under(&new_parent.path, &old.path) {
+        return Err(FsError::InvalidArgument);
+    }
+    resolve(&old_parent)?
+        .inode
+        .rename(&old_name, new_parent.inode.as_ref(), &new_name)
+}
+
+/// "."と".."を取り除いた絶対パスにする
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidArgument);
+    }
+    let mut names: Vec<&str> = Vec::new();
+    for name in components(path) {
+        if name == ".." {
+            names.pop();
+        } else {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Ok("/".to_string());
+    }
+    let mut normalized = String::new();
+    for name in names {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    Ok(normalized)
+}
+
+/// pathを親ディレクトリのパスと最後の要素に分ける
+fn split_parent(path: &str) -> Result<(String, String), FsError> {
+    let path = normalize(path)?;
+    let index = path.rfind('/').ok_or(FsError::InvalidArgument)?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        return Err(FsError::InvalidArgument);
+    }
+    let parent = if index == 0 { "/" } else { &path[..index] };
+    Ok((parent.to_string(), name.to_string()))
+}
+
+fn is_under(path: &str, mount_path: &str) -> bool {
+    mount_path == "/"
+        || path == mount_path
+        || path
+            .strip_prefix(mount_path)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
@@ -4,20 +4,17 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::vmm;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// カーネルスタックが溢れたときもページフォルトハンドラが動けるように、別のスタックを使う
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+const INTERRUPT_STACK_SIZE: u64 = 4096 * 5;
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+    // ISTのスタックはメモリの初期化が終わってからinit_interrupt_stacksで設定する
+    static ref TSS: TaskStateSegment = TaskStateSegment::new();
 }
 
 lazy_static! {
@@ -26,21 +23,57 @@ lazy_static! {
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // SYSRETはSTARに設定したセレクタ+8をSS、+16をCSとして使うので
+        // ユーザデータ、ユーザコードの順に並べる
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
                 data_selector,
                 tss_selector,
+                user_data_selector,
+                user_code_selector,
             },
         )
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Ring 3で割り込みが起きたときに切り替えるスタックを設定する
+/// プロセスを切り替えるたびに、そのプロセスのカーネルスタックを指すようにする
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    // TSSはpackedなのでアラインされていない書き込みになる
+    unsafe {
+        let rsp0 = core::ptr::addr_of!(TSS.privilege_stack_table[0]) as *mut VirtAddr;
+        rsp0.write_unaligned(stack_top);
+    }
+}
+
+/// 例外用のスタックをガードページ付きで確保してISTに設定する
+/// カーネルのアドレス空間ができてから呼ぶ
+pub fn init_interrupt_stacks() {
+    for index in [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX] {
+        let stack_top =
+            vmm::alloc_stack(INTERRUPT_STACK_SIZE).expect("failed to allocate an interrupt stack");
+        unsafe {
+            let ist =
+                core::ptr::addr_of!(TSS.interrupt_stack_table[index as usize]) as *mut VirtAddr;
+            ist.write_unaligned(stack_top);
+        }
+    }
 }
 
 pub fn init() {
@@ -1,5 +1,7 @@
 /// x86_64アーキテクチャは例外発生時に予め定義されている
 /// 既知の正常なスタックに切り替えることができる
+use core::arch::global_asm;
+
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
@@ -7,25 +9,30 @@ use spin::Mutex;
 use x86_64::{
     instructions::port::Port,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
+
+use crate::{
+    process::{scheduler::SCHEDULER, TrapFrame},
+    sync::irq_spinlock::IrqSpinlock,
+    time::{self, Instant},
 };
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinlock<ChainedPics> =
+    IrqSpinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-use crate::{gdt, println, process::scheduler::SCHEDULER};
+mod exception;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exception::install(&mut idt);
         unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(timer_interrupt_entry as usize as u64));
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
 
         idt
@@ -36,27 +43,78 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("Exception: BREAKOINT\n{:#?}", stack_frame);
+// 汎用レジスタをTrapFrameの並びでスタックに積む
+macro_rules! save_registers {
+    () => {
+        "push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15"
+    };
 }
 
-/// ダブルフォルト例外は直前の(1度目の)例外ハンドラの処理中に
-/// ２度目の例外が発生したとき起きうる
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+macro_rules! restore_registers {
+    () => {
+        "pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax"
+    };
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    static mut TICK_COUNT: u64 = 0;
+pub(crate) use restore_registers;
+pub(crate) use save_registers;
+
+// タイマ割り込みでは全レジスタを退避し、TrapFrameを書き換えることで
+// iretqの復帰先を別のプロセスに切り替える
+extern "C" {
+    fn timer_interrupt_entry();
+}
+
+global_asm!(
+    ".global timer_interrupt_entry",
+    "timer_interrupt_entry:",
+    save_registers!(),
+    "mov rdi, rsp",
+    "cld",
+    "call {handler}",
+    restore_registers!(),
+    "iretq",
+    handler = sym timer_interrupt_handler,
+);
+
+// time::TICK_HZの周波数で呼ばれる
+extern "C" fn timer_interrupt_handler(frame: &mut TrapFrame) {
     unsafe {
-        TICK_COUNT += 1;
+        time::tick();
 
-        if TICK_COUNT % 10 == 0 {
-            if let Some(schduler) = SCHEDULER.get() {
-                schduler.lock().context_switch();
+        // 割り込まれた側がロックを持っている場合は今回の処理を見送る
+        if let Some(scheduler) = SCHEDULER.get().and_then(|s| s.try_lock()) {
+            scheduler.wake_sleepers(Instant::now());
+            if scheduler.tick() {
+                scheduler.context_switch(frame);
             }
         }
         PICS.lock()
@@ -77,6 +135,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
+    crate::fs::devfs::push_scancode(scancode);
 
     unsafe {
         PICS.lock()
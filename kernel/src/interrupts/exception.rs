@@ -0,0 +1,324 @@
+//! CPU例外のハンドラ
+//!
+//! ユーザモードで起きた例外はそのプロセスだけを、終了ステータス128+ベクタ番号で終了させる
+//! カーネルで起きた例外は、カーネルの状態が壊れているかもしれないのでpanicする
+
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{
+        InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    backtrace::{self, Symbolized},
+    gdt,
+    memory::vmm::{self, Access, VmError},
+    println,
+    process::scheduler::{self, SCHEDULER},
+};
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
+
+// Ring 3のコードで起きた例外か
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+// 例外ハンドラのプロローグがスタックに積んだ、割り込まれたコードのrbp
+// force-frame-pointersでビルドしているので、ハンドラの[rbp]に入っている
+// 呼び出した先ではなく、ハンドラの本体で直接使う
+macro_rules! interrupted_rbp {
+    () => {{
+        let rbp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, [rbp]", out(reg) rbp, options(nostack, readonly, preserves_flags))
+        };
+        rbp
+    }};
+}
+
+/// 回復できない例外を報告する
+/// ユーザモードで起きたものならそのプロセスを終了させ、カーネルで起きたものならpanicする
+/// rbpは割り込まれたコードのrbpで、カーネルで起きたときにそこからバックトレースを表示する
+fn fault(
+    name: &str,
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    rbp: u64,
+    detail: fmt::Arguments,
+) -> ! {
+    if from_user(stack_frame) && SCHEDULER.get().is_some() {
+        kill_current(name, vector, stack_frame, detail);
+    }
+    backtrace::print_interrupted(stack_frame.instruction_pointer.as_u64(), rbp);
+    panic!(
+        "EXCEPTION: {} in kernel mode at {}\n{}\n{:#?}",
+        name,
+        Symbolized(stack_frame.instruction_pointer.as_u64()),
+        detail,
+        stack_frame
+    );
+}
+
+// 実行中のプロセスを終了させ、二度と戻らない
+// 次のタイマ割り込みで別のプロセスに切り替わるまで、例外用のスタックの上で待つ
+fn kill_current(
+    name: &str,
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    detail: fmt::Arguments,
+) -> ! {
+    let pid = SCHEDULER
+        .get()
+        .and_then(|scheduler| scheduler.try_lock()?.current());
+    match pid {
+        Some(pid) => println!("process {} killed: {}", pid.as_u64(), name),
+        None => println!("process killed: {}", name),
+    }
+    println!(
+        "  at {:#x}, rsp {:#x}: {}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+        detail
+    );
+    // 割り込みは禁止したまま終了させる。先に許可すると、Terminatedにする前に切り替わって
+    // 例外用のスタックの上のフレームが生きたまま残り、次の例外で上書きされる
+    scheduler::exit(128 + vector as i32)
+}
+
+// セレクタを含むエラーコードを読める形にする
+struct Selector(u64);
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match SelectorErrorCode::new(self.0) {
+            Some(code) if !code.is_null() => write!(f, "{:?}", code),
+            Some(_) => write!(f, "no selector"),
+            None => write!(f, "error code {:#x}", self.0),
+        }
+    }
+}
+
+// エラーコードの無い例外
+macro_rules! exception_handler {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fault(
+                $name,
+                $vector,
+                &stack_frame,
+                interrupted_rbp!(),
+                format_args!("no error code"),
+            );
+        }
+    };
+}
+
+// エラーコードがセグメントセレクタを指している例外
+macro_rules! selector_exception_handler {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fault(
+                $name,
+                $vector,
+                &stack_frame,
+                interrupted_rbp!(),
+                format_args!("{}", Selector(error_code)),
+            );
+        }
+    };
+}
+
+// エラーコードをそのまま表示する例外
+macro_rules! error_code_exception_handler {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fault(
+                $name,
+                $vector,
+                &stack_frame,
+                interrupted_rbp!(),
+                format_args!("error code {:#x}", error_code),
+            );
+        }
+    };
+}
+
+exception_handler!(divide_error_handler, 0, "DIVIDE ERROR (#DE)");
+exception_handler!(overflow_handler, 4, "OVERFLOW (#OF)");
+exception_handler!(
+    bound_range_exceeded_handler,
+    5,
+    "BOUND RANGE EXCEEDED (#BR)"
+);
+exception_handler!(invalid_opcode_handler, 6, "INVALID OPCODE (#UD)");
+exception_handler!(
+    device_not_available_handler,
+    7,
+    "DEVICE NOT AVAILABLE (#NM)"
+);
+exception_handler!(x87_floating_point_handler, 16, "x87 FLOATING POINT (#MF)");
+exception_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT (#XM)");
+exception_handler!(virtualization_handler, 20, "VIRTUALIZATION (#VE)");
+exception_handler!(hv_injection_handler, 28, "HYPERVISOR INJECTION (#HV)");
+selector_exception_handler!(invalid_tss_handler, 10, "INVALID TSS (#TS)");
+selector_exception_handler!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT (#NP)");
+selector_exception_handler!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT (#SS)");
+selector_exception_handler!(
+    general_protection_fault_handler,
+    13,
+    "GENERAL PROTECTION FAULT (#GP)"
+);
+error_code_exception_handler!(alignment_check_handler, 17, "ALIGNMENT CHECK (#AC)");
+error_code_exception_handler!(control_protection_handler, 21, "CONTROL PROTECTION (#CP)");
+error_code_exception_handler!(vmm_communication_handler, 29, "VMM COMMUNICATION (#VC)");
+error_code_exception_handler!(security_handler, 30, "SECURITY (#SX)");
+
+// デバッグ用の例外は報告だけして実行を続ける
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: BREAKOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: DEBUG\n{:#?}", stack_frame);
+}
+
+// NMIはハードウェアの異常を知らせるもので、どのプロセスのせいでもない
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+/// ダブルフォルト例外は直前の(1度目の)例外ハンドラの処理中に
+/// ２度目の例外が発生したとき起きうる
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    backtrace::print_interrupted(stack_frame.instruction_pointer.as_u64(), interrupted_rbp!());
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+// マシンチェックはハードウェアの故障なので、どこで起きても続けられない
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK (#MC)\n{:#?}", stack_frame);
+}
+
+/// まだフレームを割り当てていないページへのアクセスなら、ここで割り当てて命令をやり直す
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read();
+    let rbp = interrupted_rbp!();
+    if vmm::is_stack_guard(addr) {
+        stack_overflow(&stack_frame, rbp, addr);
+    }
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    let error = match vmm::handle_page_fault(addr, access) {
+        Ok(()) => return,
+        // ユーザモードならロックを持っているのは別のプロセスなので、そのまま戻ってやり直す
+        // カーネルではフォルトした側がロックを持っているかもしれず、やり直し続けて止まるのでpanicする
+        Err(VmError::Busy) if from_user(&stack_frame) => return,
+        Err(error) => error,
+    };
+
+    let cause = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let reserved = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        ", reserved bit set in a page table"
+    } else {
+        ""
+    };
+    fault(
+        "PAGE FAULT (#PF)",
+        14,
+        &stack_frame,
+        rbp,
+        format_args!(
+            "{:?} of {:#x}: {}{} ({:?})",
+            access,
+            addr.as_u64(),
+            cause,
+            reserved,
+            error
+        ),
+    );
+}
+
+// カーネルスタックが溢れてガードページに触れた
+// 溢れたところまでの状態は当てにならないので、どのプロセスかを報告してpanicする
+// 持ち主が分からないのは例外用のスタックか、スケジューラのロック中に溢れたとき
+fn stack_overflow(stack_frame: &InterruptStackFrame, rbp: u64, addr: VirtAddr) -> ! {
+    backtrace::print_interrupted(stack_frame.instruction_pointer.as_u64(), rbp);
+    let owner = SCHEDULER
+        .get()
+        .and_then(|scheduler| scheduler.try_lock()?.stack_owner(addr));
+    match owner {
+        Some(pid) => panic!(
+            "stack overflow in process {} at {} (guard page {:#x})",
+            pid.as_u64(),
+            Symbolized(stack_frame.instruction_pointer.as_u64()),
+            addr.as_u64()
+        ),
+        None => panic!(
+            "stack overflow in a kernel stack at {} (guard page {:#x})",
+            Symbolized(stack_frame.instruction_pointer.as_u64()),
+            addr.as_u64()
+        ),
+    }
+}
@@ -8,19 +8,26 @@ use bootloader_api::BootloaderConfig;
 use console::{Console, CONSOLE};
 use core::arch::asm;
 use core::panic::PanicInfo;
-use memory::BootInfoFrameAllocator;
+use core::sync::atomic::{AtomicBool, Ordering};
+use memory::BuddyFrameAllocator;
 use task::executor::{Executor, Spawner};
-use task::keyboard;
+use task::shell;
 use x86_64::instructions::port::Port;
 use x86_64::VirtAddr;
 
 mod allocator;
+mod backtrace;
 mod console;
+mod elf;
+mod fs;
 mod gdt;
 mod interrupts;
 mod memory;
 mod process;
+mod sync;
+mod syscall;
 mod task;
+mod time;
 mod usb;
 mod utils;
 mod xhci;
@@ -30,6 +37,8 @@ extern crate alloc;
 static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // 下位のアドレスはユーザ空間として使うので、ブートローダのマッピングは上位半分に置く
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
     config
 };
 
@@ -48,12 +57,13 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         CONSOLE.init_once(|| {
             let info = framebuffer.info();
             let buffer = framebuffer.buffer_mut();
-            spinning_top::Spinlock::new(Console::new(buffer, info))
+            sync::irq_spinlock::IrqSpinlock::new(Console::new(buffer, info))
         });
     }
 
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
 
     unsafe {
         interrupts::PICS.lock().initialize();
@@ -61,32 +71,70 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         master_pic.write(0xFC);
         println!("PIC mask set to 0xFC - Timer and Keyboard enabled");
     };
+    time::init();
     x86_64::instructions::interrupts::enable();
 
     let phys_mem_offset = VirtAddr::new(*boot_info.physical_memory_offset.as_ref().unwrap());
     let mut mapper: x86_64::structures::paging::OffsetPageTable<'_> =
         unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_regions) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::FRAME_ALLOCATOR.init_once(|| sync::irq_spinlock::IrqSpinlock::new(frame_allocator));
+    // プロセスのアドレス空間がカーネルの領域を共有できるように、最初のプロセスより先に作る
+    memory::vmm::init();
+    gdt::init_interrupt_stacks();
 
-    process::scheduler::SCHEDULER
-        .init_once(|| spinning_top::Spinlock::new(process::scheduler::Scheduler::new()));
+    process::scheduler::SCHEDULER.init_once(|| {
+        let policy = process::scheduler::policy::boot_policy();
+        println!("scheduler: {}", policy.name());
+        sync::irq_spinlock::IrqSpinlock::new(process::scheduler::Scheduler::new(policy))
+    });
+    if let Err(error) = process::scheduler::spawn_kernel_thread(process::scheduler::reaper) {
+        println!("failed to start the reaper: {:?}", error);
+    }
+
+    // ブートローダが読み込んだramdiskをマウントし、/bin/initを起動する
+    if let Some(ramdisk_addr) = boot_info.ramdisk_addr.into_option() {
+        let ramdisk = unsafe {
+            core::slice::from_raw_parts(ramdisk_addr as *const u8, boot_info.ramdisk_len as usize)
+        };
+        match fs::init(ramdisk) {
+            Ok(()) => {
+                if let Err(error) =
+                    process::exec::spawn_file("/bin/init", &["/bin/init"], &[], None)
+                {
+                    println!("failed to start /bin/init: {:?}", error);
+                }
+            }
+            Err(error) => println!("failed to mount the ramdisk: {:?}", error),
+        }
+    }
+
+    shell::init();
+    fs::register_commands();
+    memory::register_commands();
+    process::register_commands();
+    task::register_commands();
 
     println!("{}", OWL);
-    print!(">> ");
 
     let _result: anyhow::Result<()> = try {
         let spawner = Spawner::new(100);
         let mut executor = Executor::new(spawner.clone());
-        spawner.add(keyboard::print_keypresses());
+        spawner.add("shell", shell::run());
         executor.run();
     };
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // バックトレースを辿っている間にもう一度panicしたときは、そのまま止まる
+    static PANICKING: AtomicBool = AtomicBool::new(false);
     println!("{}", info);
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        backtrace::print();
+    }
     loop {
         unsafe { asm!("hlt") }
     }
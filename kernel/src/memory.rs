@@ -3,43 +3,39 @@ use core::mem::MaybeUninit;
 use core::pin::Pin;
 
 use alloc::boxed::Box;
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PhysFrame};
 use x86_64::{PhysAddr, VirtAddr};
 
-pub struct BootInfoFrameAllocator {
-    memory_regions: &'static MemoryRegions,
-    next: usize,
-}
+pub mod address_space;
+pub mod buddy;
+pub mod vmm;
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_regions,
-            next: 0,
-        }
-    }
+pub use buddy::BuddyFrameAllocator;
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_regions.iter();
-        let usable_regions = regions.filter(|region| region.kind == MemoryRegionKind::Usable);
-        let addr_ranges = usable_regions.map(|region| region.start..region.end);
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
+use crate::{
+    allocator, print, println, process::scheduler, sync::irq_spinlock::IrqSpinlock, task::shell,
+};
+
+pub static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+// ブート時のレベル4テーブル。プロセスのアドレス空間はこれを雛形にする
+pub static KERNEL_PAGE_TABLE: OnceCell<PhysFrame> = OnceCell::uninit();
+// ヒープを伸ばすときにアロケータのロックの中から取るので、割り込みを禁止するロックにする
+pub static FRAME_ALLOCATOR: OnceCell<IrqSpinlock<BuddyFrameAllocator>> = OnceCell::uninit();
+
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("physical memory offset is not initialized")
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
+// 物理アドレスをphysical memory offsetでマップされた仮想アドレスに変換する
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_page_frame, _) = Cr3::read();
 
     let phys = level_4_page_frame.start_address();
@@ -50,6 +46,8 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_PAGE_TABLE.init_once(|| Cr3::read().0);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -99,3 +97,273 @@ impl<T: Sized> Default for IoBox<T> {
         Self::new()
     }
 }
+
+pub fn register_commands() {
+    shell::register("meminfo", "show memory usage", |_args| {
+        println!(
+            "heap:   {} / {} KiB at {:#x}",
+            allocator::heap_size() / 1024,
+            allocator::HEAP_MAX_SIZE / 1024,
+            allocator::HEAP_START
+        );
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.get() {
+            let stats = frame_allocator.lock().stats();
+            println!(
+                "frames: {} / {} used ({} / {} KiB)",
+                stats.used,
+                stats.total,
+                stats.used * 4,
+                stats.total * 4
+            );
+            println!("splits: {}, merges: {}", stats.splits, stats.merges);
+            print!("free blocks:");
+            for (order, count) in stats.free_blocks.iter().enumerate() {
+                print!(" {}K:{}", 4 << order, count);
+            }
+            println!();
+        }
+    });
+    shell::register(
+        "vmas",
+        "show virtual memory areas of the kernel or a process",
+        |args| {
+            let vmas = match args.get(1) {
+                None => vmm::kernel_vmas(),
+                Some(pid) => {
+                    let address_space = pid
+                        .parse()
+                        .ok()
+                        .and_then(|pid| scheduler::SCHEDULER.get()?.lock().address_space(pid));
+                    let Some(address_space) = address_space else {
+                        println!("vmas: no address space for process {}", pid);
+                        return;
+                    };
+                    let vmas = address_space.lock().vmas().cloned().collect();
+                    vmas
+                }
+            };
+            for vma in vmas {
+                println!(
+                    "{:#014x}-{:#014x} {} {}",
+                    vma.start(),
+                    vma.end(),
+                    vma.protection(),
+                    vma.backing().name()
+                );
+            }
+        },
+    );
+    shell::register(
+        "mmap",
+        "map an area into the kernel (mmap len prot [file path [offset] | device phys])",
+        mmap_command,
+    );
+    shell::register(
+        "munmap",
+        "unmap an area of the kernel (munmap addr len)",
+        |args| {
+            let (Some(addr), Some(len)) = (arg_u64(args, 1), arg_u64(args, 2)) else {
+                println!("usage: munmap addr len");
+                return;
+            };
+            if let Err(error) = VirtAddr::try_new(addr)
+                .map_err(|_| vmm::VmError::InvalidArgument)
+                .and_then(|addr| vmm::kernel_munmap(addr, len))
+            {
+                println!("munmap: {:?}", error);
+            }
+        },
+    );
+    shell::register(
+        "mprotect",
+        "change the protection of a kernel area (mprotect addr len prot)",
+        |args| {
+            let (Some(addr), Some(len), Some(protection)) =
+                (arg_u64(args, 1), arg_u64(args, 2), arg_u64(args, 3))
+            else {
+                println!("usage: mprotect addr len prot");
+                return;
+            };
+            if let Err(error) = VirtAddr::try_new(addr)
+                .map_err(|_| vmm::VmError::InvalidArgument)
+                .and_then(|addr| {
+                    vmm::kernel_mprotect(addr, len, vmm::Protection::from_bits(protection)?)
+                })
+            {
+                println!("mprotect: {:?}", error);
+            }
+        },
+    );
+    #[cfg(not(feature = "slab-allocator"))]
+    shell::register("heapinfo", "show heap statistics", |_args| {
+        // 表示中に確保しないように、統計をコピーしてからロックを外す
+        let stats = allocator::ALLOCATOR.lock().stats();
+        println!(
+            "{:>8} {:>8} {:>8} {:>10} {:>10} {:>6}",
+            "block", "allocs", "frees", "live", "peak", "free"
+        );
+        for class in stats
+            .classes
+            .iter()
+            .chain(core::iter::once(&stats.fallback))
+        {
+            if class.block_size == 0 {
+                print!("{:>8}", "fallback");
+            } else {
+                print!("{:>8}", class.block_size);
+            }
+            println!(
+                " {:>8} {:>8} {:>10} {:>10} {:>6}",
+                class.allocs, class.frees, class.live_bytes, class.peak_bytes, class.free_blocks
+            );
+        }
+        println!(
+            "fallback heap: {} / {} bytes used, largest free block {} bytes, {}% fragmented",
+            stats.heap_used,
+            stats.heap_size,
+            stats.largest_free,
+            stats.fragmentation()
+        );
+    });
+    #[cfg(all(feature = "alloc-tracking", not(feature = "slab-allocator")))]
+    shell::register(
+        "leaks",
+        "show live allocations by call site (leaks [since])",
+        show_leaks,
+    );
+    #[cfg(feature = "slab-allocator")]
+    shell::register("slabinfo", "show slab allocator caches", |_args| {
+        // 表示中に確保しないように、統計をコピーしてからロックを外す
+        let stats = allocator::ALLOCATOR.lock().stats();
+        println!(
+            "{:14} {:>6} {:>6} {:>9} {:>16} {:>9}",
+            "name", "size", "slab", "objects", "full/part/empty", "reclaimed"
+        );
+        for cache in stats.iter().flatten() {
+            let slabs = cache.full_slabs + cache.partial_slabs + cache.empty_slabs;
+            println!(
+                "{:14} {:>6} {:>5}K {:>4}/{:<4} {:>5}/{:>4}/{:<5} {:>9}",
+                cache.name,
+                cache.object_size,
+                cache.slab_size / 1024,
+                cache.in_use,
+                slabs * cache.objects_per_slab,
+                cache.full_slabs,
+                cache.partial_slabs,
+                cache.empty_slabs,
+                cache.reclaimed
+            );
+        }
+    });
+}
+
+// 確保した番号がsince以降で、まだ解放されていない領域を呼び出し元ごとにまとめて表示する
+// args[index]を数として読む。0xで始まれば16進数
+fn arg_u64(args: &[&str], index: usize) -> Option<u64> {
+    let arg = args.get(index)?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+// カーネルのアドレス空間に領域を登録してアドレスを表示する
+// protはLinuxのPROT_*と同じ値(1: 読み, 2: 書き, 4: 実行)
+fn mmap_command(args: &[&str]) {
+    const USAGE: &str = "usage: mmap len prot [file path [offset] | device phys]";
+    let (Some(len), Some(protection)) = (arg_u64(args, 1), arg_u64(args, 2)) else {
+        println!("{}", USAGE);
+        return;
+    };
+    let backing = match args.get(3).copied() {
+        None => vmm::Backing::Anonymous,
+        Some("file") => {
+            let Some(path) = args.get(4) else {
+                println!("{}", USAGE);
+                return;
+            };
+            let inode = match crate::fs::vfs::resolve(path) {
+                Ok(dentry) => dentry.inode().clone(),
+                Err(error) => {
+                    println!("mmap: {}: {:?}", path, error);
+                    return;
+                }
+            };
+            vmm::Backing::File {
+                inode,
+                offset: arg_u64(args, 5).unwrap_or(0),
+            }
+        }
+        Some("device") => {
+            let Some(phys) = arg_u64(args, 4).and_then(|phys| PhysAddr::try_new(phys).ok()) else {
+                println!("{}", USAGE);
+                return;
+            };
+            vmm::Backing::Device { phys }
+        }
+        Some(_) => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    match vmm::Protection::from_bits(protection)
+        .and_then(|protection| vmm::kernel_mmap(len, protection, backing))
+    {
+        Ok(addr) => println!("{:#x}", addr.as_u64()),
+        Err(error) => println!("mmap: {:?}", error),
+    }
+}
+
+#[cfg(all(feature = "alloc-tracking", not(feature = "slab-allocator")))]
+fn show_leaks(args: &[&str]) {
+    use alloc::vec::Vec;
+    use allocator::tracking::{Allocation, MAX_TRACKED};
+
+    let since = match args.get(1).map(|arg| arg.parse::<u64>()) {
+        None => 0,
+        Some(Ok(since)) => since,
+        Some(Err(_)) => {
+            println!("usage: leaks [since]");
+            return;
+        }
+    };
+    // ロックを持っている間に確保しないように、先に表全体が入る大きさを確保しておく
+    let mut allocations: Vec<Allocation> = Vec::with_capacity(MAX_TRACKED);
+    let (next_sequence, untracked) = {
+        let allocator = allocator::ALLOCATOR.lock();
+        allocations.extend(
+            allocator
+                .tracked()
+                .filter(|allocation| allocation.sequence >= since),
+        );
+        allocator.tracking_state()
+    };
+    // このコマンド自身が確保したものは除く
+    let own = allocations.as_ptr() as usize;
+    allocations.retain(|allocation| allocation.address != own);
+
+    allocations.sort_unstable_by_key(|allocation| allocation.callers);
+    for group in allocations.chunk_by(|a, b| a.callers == b.callers) {
+        let bytes: usize = group.iter().map(|allocation| allocation.size).sum();
+        print!("{:>5} {:>8}  ", group.len(), bytes);
+        for (depth, caller) in group[0]
+            .callers
+            .iter()
+            .take_while(|&&caller| caller != 0)
+            .enumerate()
+        {
+            if depth > 0 {
+                print!(" <- ");
+            }
+            print!("{:#x}", caller);
+        }
+        println!();
+    }
+    println!(
+        "{} live allocations since #{}, next #{}, {} untracked",
+        allocations.len(),
+        since,
+        next_sequence,
+        untracked
+    );
+}
@@ -0,0 +1,84 @@
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
+
+use super::{phys_to_virt, physical_memory_offset, KERNEL_PAGE_TABLE};
+
+// レベル4テーブルの先頭128エントリ(下位64TiB)をユーザ空間とする
+// それ以外のエントリ(HEAP_STARTや物理メモリのマッピングを含む)は全プロセスで共有する
+pub const USER_LEVEL_4_ENTRIES: usize = 128;
+pub const USER_SPACE_START: u64 = 0x1000;
+pub const USER_SPACE_END: u64 = (USER_LEVEL_4_ENTRIES as u64) << 39;
+
+// ユーザスタックはユーザ空間の末尾に置く
+pub const USER_STACK_TOP: u64 = USER_SPACE_END;
+pub const USER_STACK_SIZE: u64 = 4096 * 16;
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// カーネル部分をブート時のテーブルからコピーした新しいレベル4テーブルを作る
+pub fn new_level_4_table(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<(PhysFrame, &'static mut PageTable)> {
+    let frame = frame_allocator.allocate_frame()?;
+    let template = unsafe {
+        table_at(
+            *KERNEL_PAGE_TABLE
+                .get()
+                .expect("kernel page table is not initialized"),
+        )
+    };
+
+    let table = unsafe { table_at(frame) };
+    table.zero();
+    for i in USER_LEVEL_4_ENTRIES..512 {
+        table[i] = template[i].clone();
+    }
+    Some((frame, table))
+}
+
+/// プロセスのレベル4テーブルを操作するためのMapperを作る
+pub unsafe fn mapper(level_4_table: &mut PageTable) -> OffsetPageTable<'_> {
+    OffsetPageTable::new(level_4_table, physical_memory_offset())
+}
+
+/// ユーザ空間にマップされたフレームとページテーブル自身をすべて解放する
+/// 共有しているカーネル部分には触らない
+pub unsafe fn free_level_4_table(
+    level_4_frame: PhysFrame,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let level_4_table = table_at(level_4_frame);
+    for entry in level_4_table.iter_mut().take(USER_LEVEL_4_ENTRIES) {
+        if let Ok(frame) = entry.frame() {
+            free_table(frame, 3, frame_allocator);
+        }
+        entry.set_unused();
+    }
+    frame_allocator.deallocate_frame(level_4_frame);
+}
+
+// levelは解放するテーブルの段数(レベル1のテーブルは1)
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = table_at(frame);
+    for entry in table.iter() {
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+        if let Ok(child) = entry.frame() {
+            if level == 1 {
+                frame_allocator.deallocate_frame(child);
+            } else {
+                free_table(child, level - 1, frame_allocator);
+            }
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
@@ -0,0 +1,264 @@
+//! バディシステムによる物理フレームアロケータ
+//!
+//! 2^orderフレームのブロック単位で管理し、ブロックは自分の大きさの境界に揃っている
+//! 確保するときは大きなブロックを半分ずつに分け、解放するときは空いている相方(バディ)と
+//! まとめ直す。order 9のブロックがちょうど2MiBのページになる
+//!
+//! 空きブロックのリストは、空いているフレーム自体の先頭に次と前のフレーム番号を書いてつなぐ
+//!
+//! コピーオンライトで複数のアドレス空間にマップされたフレームは、共有している数を数えておき
+//! 最後の1つが解放されたときに空きに戻す
+
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = 4096;
+pub const ORDERS: usize = 11; // 4KiBから4MiBまで
+const HUGE_PAGE_ORDER: usize = 9; // 2MiB
+const NONE: u64 = u64::MAX;
+const NOT_FREE: u8 = u8::MAX;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyStats {
+    pub total: usize,
+    pub used: usize,
+    // ブロックを分けた回数と、まとめ直した回数
+    pub splits: usize,
+    pub merges: usize,
+    // orderごとの空きブロックの数
+    pub free_blocks: [usize; ORDERS],
+}
+
+pub struct BuddyFrameAllocator {
+    // フレームごとに、空きブロックの先頭ならそのorder、それ以外はNOT_FREE
+    orders: &'static mut [u8],
+    // フレームごとの、そのフレームを共有している数-1
+    shares: &'static mut [u16],
+    // orderごとの空きブロックのリストの先頭のフレーム番号
+    free_lists: [u64; ORDERS],
+    free_blocks: [usize; ORDERS],
+    total: usize,
+    free: usize,
+    splits: usize,
+    merges: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// memory_regionsの使用可能な領域を空きブロックにする
+    /// physical memory offsetが初期化されている必要がある
+    pub unsafe fn init(memory_regions: &MemoryRegions) -> Self {
+        let usable = || {
+            memory_regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+                .map(|region| (region.start.div_ceil(FRAME_SIZE), region.end / FRAME_SIZE))
+        };
+        let frames = usable().map(|(_, end)| end).max().unwrap_or(0);
+
+        // フレームごとのorderと共有数の表を置ける領域を探し、その分は空きにしない
+        // 共有数の表はu16の境界に揃えてorderの表の後ろに置く
+        let shares_offset = frames.next_multiple_of(2);
+        let table_frames = (shares_offset + frames * 2).div_ceil(FRAME_SIZE);
+        let table_start = usable()
+            .find(|(start, end)| start + table_frames <= *end)
+            .map(|(start, _)| start)
+            .expect("no usable memory region for the buddy allocator");
+        let table = phys_to_virt(PhysAddr::new(table_start * FRAME_SIZE)).as_mut_ptr::<u8>();
+        let orders = core::slice::from_raw_parts_mut(table, frames as usize);
+        orders.fill(NOT_FREE);
+        let shares = core::slice::from_raw_parts_mut(
+            table.add(shares_offset as usize).cast::<u16>(),
+            frames as usize,
+        );
+        shares.fill(0);
+
+        let mut allocator = Self {
+            orders,
+            shares,
+            free_lists: [NONE; ORDERS],
+            free_blocks: [0; ORDERS],
+            total: 0,
+            free: 0,
+            splits: 0,
+            merges: 0,
+        };
+        let table_end = table_start + table_frames;
+        for (start, end) in usable() {
+            if start < table_end && table_start < end {
+                allocator.add_range(start, table_start);
+                allocator.add_range(table_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+        allocator
+    }
+
+    // [start, end)のフレームを、できるだけ大きなブロックに分けて空きにする
+    fn add_range(&mut self, mut start: u64, end: u64) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(ORDERS - 1);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.push(start, order);
+            start += 1 << order;
+            self.total += 1 << order;
+            self.free += 1 << order;
+        }
+    }
+
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            total: self.total,
+            used: self.total - self.free,
+            splits: self.splits,
+            merges: self.merges,
+            free_blocks: self.free_blocks,
+        }
+    }
+
+    // 空きフレームの先頭に書いた[次, 前]のフレーム番号
+    fn links(index: u64) -> &'static mut [u64; 2] {
+        unsafe { &mut *phys_to_virt(PhysAddr::new(index * FRAME_SIZE)).as_mut_ptr::<[u64; 2]>() }
+    }
+
+    fn push(&mut self, index: u64, order: usize) {
+        let head = self.free_lists[order];
+        *Self::links(index) = [head, NONE];
+        if head != NONE {
+            Self::links(head)[1] = index;
+        }
+        self.free_lists[order] = index;
+        self.orders[index as usize] = order as u8;
+        self.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, index: u64, order: usize) {
+        let [next, prev] = *Self::links(index);
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            Self::links(prev)[0] = next;
+        }
+        if next != NONE {
+            Self::links(next)[1] = prev;
+        }
+        self.orders[index as usize] = NOT_FREE;
+        self.free_blocks[order] -= 1;
+    }
+
+    /// 2^orderフレームのブロックを確保し、先頭のフレーム番号を返す
+    fn allocate_order(&mut self, order: usize) -> Option<u64> {
+        let mut current = (order..ORDERS).find(|&order| self.free_lists[order] != NONE)?;
+        let index = self.free_lists[current];
+        self.remove(index, current);
+        // 大きすぎるブロックは後ろ半分を空きに戻していく
+        while current > order {
+            current -= 1;
+            self.push(index + (1 << current), current);
+            self.splits += 1;
+        }
+        self.free -= 1 << order;
+        Some(index)
+    }
+
+    fn deallocate_order(&mut self, mut index: u64, mut order: usize) {
+        self.free += 1 << order;
+        while order < ORDERS - 1 {
+            let buddy = index ^ (1 << order);
+            if self.orders.get(buddy as usize) != Some(&(order as u8)) {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+            self.merges += 1;
+        }
+        self.push(index, order);
+    }
+
+    /// 物理的に連続したcount個のフレームを確保し、先頭のフレームを返す
+    /// 先頭のフレームはalignフレーム(2の冪)の境界に揃える
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 {
+            return None;
+        }
+        let order = count.next_power_of_two().max(align).trailing_zeros() as usize;
+        if order >= ORDERS {
+            return None;
+        }
+        let start = self.allocate_order(order)?;
+        // 使わない後ろのフレームは1つずつ返し、まとめられるところはまとめ直す
+        for index in start + count as u64..start + (1 << order) {
+            self.deallocate_order(index, 0);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            start * FRAME_SIZE,
+        )))
+    }
+
+    /// allocate_contiguousで確保したフレームを返す
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let start = start.start_address().as_u64() / FRAME_SIZE;
+        for index in start..start + count as u64 {
+            self.deallocate_order(index, 0);
+        }
+    }
+}
+
+impl BuddyFrameAllocator {
+    /// frameを共有するアドレス空間が1つ増えたことを記録する
+    /// deallocate_frameが共有している数だけ呼ばれたときに空きに戻る
+    pub fn share(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many shares of a frame");
+    }
+
+    /// frameが複数のアドレス空間から共有されているか
+    pub fn is_shared(&self, frame: PhysFrame<Size4KiB>) -> bool {
+        self.shares[(frame.start_address().as_u64() / FRAME_SIZE) as usize] > 0
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let index = self.allocate_order(0)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index * FRAME_SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = frame.start_address().as_u64() / FRAME_SIZE;
+        // まだ他のアドレス空間が使っているなら、共有している数を減らすだけ
+        if let Some(shares) = self.shares[index as usize].checked_sub(1) {
+            self.shares[index as usize] = shares;
+            return;
+        }
+        self.deallocate_order(index, 0);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let index = self.allocate_order(HUGE_PAGE_ORDER)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index * FRAME_SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_order(frame.start_address().as_u64() / FRAME_SIZE, HUGE_PAGE_ORDER);
+    }
+}
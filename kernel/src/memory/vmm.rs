@@ -0,0 +1,831 @@
+//! VMA(仮想メモリ領域)によるアドレス空間の管理とデマンドページング
+//!
+//! mmapは領域と権限と中身の出どころ(backing)を登録するだけで、フレームは
+//! 最初にアクセスされてページフォルトが起きたときに割り当てる
+//!
+//! forkしたアドレス空間はフレームを書き込み禁止で共有し、書き込まれたときにコピーする
+//!
+//! ユーザ空間はプロセスごとのAddressSpaceで、カーネルのサブシステム向けには
+//! 全プロセスで共有するレベル4エントリ1つ分(512GiB)をカーネルのAddressSpaceとして使う
+//! カーネルスタックはもう1つのレベル4エントリに、下にガードページを挟んで並べる
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::ops::Range;
+use spinning_top::Spinlock;
+use x86_64::structures::paging::{
+    mapper::TranslateResult, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable,
+    PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{
+    address_space::{self, USER_LEVEL_4_ENTRIES, USER_SPACE_END, USER_SPACE_START},
+    phys_to_virt, BuddyFrameAllocator, FRAME_ALLOCATOR, KERNEL_PAGE_TABLE,
+};
+use crate::{
+    fs::vfs::Inode,
+    process::scheduler::SCHEDULER,
+    sync::irq_spinlock::{IrqSpinlock, IrqSpinlockGuard},
+};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+// アドレスを指定しないmmapで、ユーザ空間のどこから空きを探すか
+const USER_MMAP_BASE: u64 = 0x1000_0000_0000;
+
+// カーネルスタック1つ分の領域。一番下のページはガードページとしてマップしない
+const STACK_SLOT_SIZE: u64 = 64 * 1024;
+
+static KERNEL_ADDRESS_SPACE: OnceCell<Spinlock<AddressSpace>> = OnceCell::uninit();
+// スケジューラのロックを持ったままプロセスを作るときにも取るので、割り込みを禁止するロックにする
+// そうしないと、解放の途中で横取りされたプロセスを割り込み禁止のまま待ち続けることがある
+static KERNEL_STACKS: OnceCell<IrqSpinlock<AddressSpace>> = OnceCell::uninit();
+// ページフォルトハンドラがロックを取らずにガードページかを判定できるように、範囲だけ別に持つ
+static STACK_REGION: OnceCell<Range<u64>> = OnceCell::uninit();
+
+/// 領域に許すアクセス
+/// 値はLinuxのPROT_READなどに合わせる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Self = Self(0);
+    pub const READ: Self = Self(1);
+    pub const WRITE: Self = Self(2);
+    pub const EXECUTE: Self = Self(4);
+
+    pub fn from_bits(bits: u64) -> Result<Self, VmError> {
+        if bits & !7 != 0 {
+            return Err(VmError::InvalidArgument);
+        }
+        Ok(Self(bits as u8))
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.0 != 0,
+            Access::Write => self.contains(Self::WRITE),
+            Access::Execute => self.contains(Self::EXECUTE),
+        }
+    }
+
+    // x86_64では読めないページを作れないので、NONEのユーザページはカーネルからだけ見えるようにする
+    fn page_flags(self, user: bool) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.contains(Self::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Self::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if user && self != Self::NONE {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        flags
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// ページフォルトを起こしたアクセスの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// 領域の中身の出どころ
+#[derive(Clone)]
+pub enum Backing {
+    // ゼロ埋めしたフレーム
+    Anonymous,
+    // ファイルのoffsetからの内容をコピーしたフレーム。書き込んでもファイルには反映しない
+    File { inode: Arc<dyn Inode>, offset: u64 },
+    // 物理アドレスをそのままマップする(MMIOなど)。フレームは解放しない
+    Device { phys: PhysAddr },
+}
+
+impl Backing {
+    // 領域の先頭からdeltaバイト後ろから始まる領域の出どころ
+    fn advanced(&self, delta: u64) -> Self {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { inode, offset } => Backing::File {
+                inode: inode.clone(),
+                offset: offset + delta,
+            },
+            Backing::Device { phys } => Backing::Device {
+                phys: *phys + delta,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backing::Anonymous => "anonymous",
+            Backing::File { .. } => "file",
+            Backing::Device { .. } => "device",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    start: u64,
+    end: u64,
+    protection: Protection,
+    backing: Backing,
+}
+
+impl Vma {
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.start)
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new(self.end)
+    }
+
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    pub fn backing(&self) -> &Backing {
+        &self.backing
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::containing_address(VirtAddr::new(self.start));
+        let end = Page::containing_address(VirtAddr::new(self.end));
+        Page::range(start, end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    // アドレスや長さがページ境界に揃っていない、範囲外など
+    InvalidArgument,
+    // 既にある領域と重なっている
+    Overlap,
+    // 空いている場所が見つからない
+    NoSpace,
+    OutOfMemory,
+    // アドレスを含む領域が無い
+    NotMapped,
+    // 領域の権限で許されていないアクセス
+    AccessDenied,
+    // ファイルの読み込みに失敗した
+    Io,
+    // ページフォルトの処理に必要なロックを、割り込まれた側が持っていた
+    Busy,
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // VMAを置ける範囲と、アドレスを指定しないmmapで空きを探し始める位置
+    range: Range<u64>,
+    mmap_base: u64,
+    user: bool,
+    // 先頭アドレスをキーにした、重ならない領域
+    vmas: BTreeMap<u64, Vma>,
+}
+
+impl AddressSpace {
+    /// カーネル部分を共有する新しいユーザのアドレス空間を作る
+    pub fn new_user() -> Result<Self, VmError> {
+        let mut frame_allocator = frame_allocator(true)?;
+        let (level_4_frame, _) =
+            address_space::new_level_4_table(&mut *frame_allocator).ok_or(VmError::OutOfMemory)?;
+        Ok(Self {
+            level_4_frame,
+            range: USER_SPACE_START..USER_SPACE_END,
+            mmap_base: USER_MMAP_BASE,
+            user: true,
+            vmas: BTreeMap::new(),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn level_4_table(&self) -> &'static mut PageTable {
+        unsafe { &mut *phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr::<PageTable>() }
+    }
+
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// addrを含む領域
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        let addr = addr.as_u64();
+        self.vmas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| addr < vma.end)
+    }
+
+    // [start, start + len)をページ境界に広げ、範囲内にあることを確かめる
+    fn page_range(&self, start: u64, len: u64) -> Result<Range<u64>, VmError> {
+        if start % PAGE_SIZE != 0 || len == 0 {
+            return Err(VmError::InvalidArgument);
+        }
+        let end = start
+            .checked_add(len)
+            .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+            .ok_or(VmError::InvalidArgument)?;
+        if start < self.range.start || end > self.range.end {
+            return Err(VmError::InvalidArgument);
+        }
+        Ok(start..end)
+    }
+
+    // lenバイトが入る空きをmmap_baseから探す
+    fn find_free(&self, len: u64) -> Result<u64, VmError> {
+        let len = len.next_multiple_of(PAGE_SIZE);
+        let mut candidate = self.mmap_base;
+        for vma in self.vmas.values().filter(|vma| vma.end > self.mmap_base) {
+            if vma.start >= candidate + len {
+                break;
+            }
+            candidate = candidate.max(vma.end);
+        }
+        if candidate + len > self.range.end {
+            return Err(VmError::NoSpace);
+        }
+        Ok(candidate)
+    }
+
+    /// 領域を登録する。フレームは最初にアクセスされたときに割り当てる
+    /// addrがNoneのときは空いている場所を探し、登録した先頭アドレスを返す
+    pub fn mmap(
+        &mut self,
+        addr: Option<VirtAddr>,
+        len: u64,
+        protection: Protection,
+        backing: Backing,
+    ) -> Result<VirtAddr, VmError> {
+        if len == 0 {
+            return Err(VmError::InvalidArgument);
+        }
+        let start = match addr {
+            Some(addr) => addr.as_u64(),
+            None => self.find_free(len)?,
+        };
+        let range = self.page_range(start, len)?;
+        if self.overlapping(&range).next().is_some() {
+            return Err(VmError::Overlap);
+        }
+        self.vmas.insert(
+            range.start,
+            Vma {
+                start: range.start,
+                end: range.end,
+                protection,
+                backing,
+            },
+        );
+        Ok(VirtAddr::new(range.start))
+    }
+
+    /// [addr, addr + len)の領域を外し、割り当てていたフレームを解放する
+    pub fn munmap(&mut self, addr: VirtAddr, len: u64) -> Result<(), VmError> {
+        let range = self.page_range(addr.as_u64(), len)?;
+        self.split_at(range.start);
+        self.split_at(range.end);
+        let starts: Vec<u64> = self.overlapping(&range).map(|vma| vma.start).collect();
+        for start in starts {
+            if let Some(vma) = self.vmas.remove(&start) {
+                self.unmap_pages(&vma)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// [addr, addr + len)の領域の権限を変える。マップ済みのページのフラグも書き換える
+    pub fn mprotect(
+        &mut self,
+        addr: VirtAddr,
+        len: u64,
+        protection: Protection,
+    ) -> Result<(), VmError> {
+        let range = self.page_range(addr.as_u64(), len)?;
+        // 範囲に穴があるときは何も変えずに失敗する
+        let mut covered = range.start;
+        for vma in self.overlapping(&range) {
+            if vma.start > covered {
+                return Err(VmError::NotMapped);
+            }
+            covered = vma.end;
+        }
+        if covered < range.end {
+            return Err(VmError::NotMapped);
+        }
+
+        self.split_at(range.start);
+        self.split_at(range.end);
+        let flags = protection.page_flags(self.user);
+        let mut mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        let frame_allocator = frame_allocator(true)?;
+        for vma in self
+            .vmas
+            .range_mut(range.start..range.end)
+            .map(|(_, vma)| vma)
+        {
+            vma.protection = protection;
+            let device = matches!(vma.backing, Backing::Device { .. });
+            for page in vma.pages() {
+                let Ok(frame) = mapper.translate_page(page) else {
+                    continue;
+                };
+                let mut flags = flags;
+                if device {
+                    flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+                } else if frame_allocator.is_shared(frame) {
+                    // 共有しているフレームは、書き込まれたときにコピーするので書き込み禁止のままにする
+                    flags.remove(PageTableFlags::WRITABLE);
+                }
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                    flush.flush();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// ページフォルトを処理する。領域の中で権限が許すアクセスならフレームを割り当ててマップする
+    pub fn handle_fault(&mut self, addr: VirtAddr, access: Access) -> Result<(), VmError> {
+        let vma = self.find(addr).ok_or(VmError::NotMapped)?;
+        if !vma.protection.allows(access) {
+            return Err(VmError::AccessDenied);
+        }
+        let page = Page::containing_address(addr);
+        let mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        // マップ済みのページでのフォルトは、ページの権限が足りなかったということ
+        // 書き込みが許された領域で書き込み禁止になっているのはコピーオンライトのページ
+        if let TranslateResult::Mapped { flags, .. } = mapper.translate(addr) {
+            if access == Access::Write && !flags.contains(PageTableFlags::WRITABLE) {
+                return self.copy_on_write(vma, page, false);
+            }
+            return Err(VmError::AccessDenied);
+        }
+        self.map_page(vma, page, false)
+    }
+
+    /// [addr, addr + len)のまだマップしていないページを先に割り当てておく
+    /// accessがWriteのときは、共有しているフレームを自分用にコピーしておく
+    pub fn populate(&mut self, addr: VirtAddr, len: u64, access: Access) -> Result<(), VmError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let start = Page::<Size4KiB>::containing_address(addr);
+        let end = Page::containing_address(addr + (len - 1));
+        let mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        for page in Page::range_inclusive(start, end) {
+            let vma = self.find(page.start_address()).ok_or(VmError::NotMapped)?;
+            match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => {
+                    if access == Access::Write && !flags.contains(PageTableFlags::WRITABLE) {
+                        self.copy_on_write(vma, page, true)?;
+                    }
+                }
+                _ => self.map_page(vma, page, true)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// addrにdataを書き込む。ページの権限に関係なく、物理メモリのマッピング経由で書く
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), VmError> {
+        self.populate(addr, data.len() as u64, Access::Write)?;
+        let mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written;
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let frame = mapper
+                .translate_page(page)
+                .map_err(|_| VmError::NotMapped)?;
+            let offset = addr - page.start_address();
+            let len = ((PAGE_SIZE - offset) as usize).min(data.len() - written);
+            unsafe {
+                phys_to_virt(frame.start_address() + offset)
+                    .as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(data[written..].as_ptr(), len);
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// [addr, addr + len)全体が、accessを許す領域に含まれているか
+    pub fn check_access(&self, addr: VirtAddr, len: u64, access: Access) -> bool {
+        let start = addr.as_u64();
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        if start < self.range.start || end > self.range.end {
+            return false;
+        }
+        let mut covered = start;
+        while covered < end {
+            match self.find(VirtAddr::new(covered)) {
+                Some(vma) if vma.protection.allows(access) => covered = vma.end,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn overlapping<'a>(&'a self, range: &Range<u64>) -> impl Iterator<Item = &'a Vma> {
+        let (start, end) = (range.start, range.end);
+        // startより前から始まってstartにかかっている領域も含める
+        let first = self
+            .find(VirtAddr::new(start))
+            .map_or(start, |vma| vma.start);
+        self.vmas
+            .range(first..end)
+            .map(|(_, vma)| vma)
+            .filter(move |vma| vma.end > start)
+    }
+
+    // addrをまたぐ領域があれば、addrの前後で2つに分ける
+    fn split_at(&mut self, addr: u64) {
+        let Some(vma) = self.find(VirtAddr::new(addr)) else {
+            return;
+        };
+        if vma.start == addr {
+            return;
+        }
+        let tail = Vma {
+            start: addr,
+            end: vma.end,
+            protection: vma.protection,
+            backing: vma.backing.advanced(addr - vma.start),
+        };
+        let start = vma.start;
+        self.vmas.get_mut(&start).unwrap().end = addr;
+        self.vmas.insert(addr, tail);
+    }
+
+    // vmaのpageにフレームを割り当ててマップする
+    // ページフォルトの処理中はwaitをfalseにし、フレームアロケータのロックを待たない
+    fn map_page(&self, vma: &Vma, page: Page, wait: bool) -> Result<(), VmError> {
+        let offset = page.start_address().as_u64() - vma.start;
+        let mut flags = vma.protection.page_flags(self.user);
+        let frame = match &vma.backing {
+            Backing::Device { phys } => {
+                flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+                PhysFrame::containing_address(*phys + offset)
+            }
+            Backing::Anonymous => zeroed_frame(wait)?,
+            Backing::File {
+                inode,
+                offset: file_offset,
+            } => {
+                let frame = zeroed_frame(wait)?;
+                let contents = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                        PAGE_SIZE as usize,
+                    )
+                };
+                // ファイルの終わりより後ろはゼロのまま残す
+                if inode.read_at(file_offset + offset, contents).is_err() {
+                    unsafe { frame_allocator(wait)?.deallocate_frame(frame) };
+                    return Err(VmError::Io);
+                }
+                frame
+            }
+        };
+
+        let mut frame_allocator = frame_allocator(wait)?;
+        let mut mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => {
+                if !matches!(vma.backing, Backing::Device { .. }) {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                Err(VmError::OutOfMemory)
+            }
+        }
+    }
+
+    // 書き込み禁止になっているvmaのpageを、領域の権限に戻す
+    // 他のアドレス空間と共有しているフレームなら、中身をコピーした自分用のフレームに差し替える
+    fn copy_on_write(&self, vma: &Vma, page: Page, wait: bool) -> Result<(), VmError> {
+        if matches!(vma.backing, Backing::Device { .. }) {
+            return Ok(());
+        }
+        let flags = vma.protection.page_flags(self.user);
+        let mut frame_allocator = frame_allocator(wait)?;
+        let mut mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        let frame = mapper
+            .translate_page(page)
+            .map_err(|_| VmError::NotMapped)?;
+        if !frame_allocator.is_shared(frame) {
+            // 他のアドレス空間はもうコピーを持っているので、そのまま使える
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+            return Ok(());
+        }
+
+        let copy = frame_allocator
+            .allocate_frame()
+            .ok_or(VmError::OutOfMemory)?;
+        unsafe {
+            phys_to_virt(copy.start_address())
+                .as_mut_ptr::<u8>()
+                .copy_from_nonoverlapping(
+                    phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    PAGE_SIZE as usize,
+                );
+        }
+        let (_, flush) = mapper.unmap(page).map_err(|_| VmError::NotMapped)?;
+        flush.ignore();
+        // ページテーブルは残っているので、新しく割り当てることはない
+        unsafe { mapper.map_to(page, copy, flags, &mut *frame_allocator) }
+            .map_err(|_| VmError::OutOfMemory)?
+            .flush();
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        Ok(())
+    }
+
+    /// 同じ領域を持つ新しいアドレス空間を作る
+    /// フレームはコピーせずに両方から書き込み禁止で共有し、書き込まれたときにコピーする
+    pub fn fork(&self) -> Result<AddressSpace, VmError> {
+        let mut child = AddressSpace::new_user()?;
+        child.range = self.range.clone();
+        child.mmap_base = self.mmap_base;
+        child.vmas = self.vmas.clone();
+        let mut frame_allocator = frame_allocator(true)?;
+        let result = self.share_pages(&child, &mut frame_allocator);
+        // 失敗したときのchildのdropはフレームアロケータをロックするので、先に外す
+        drop(frame_allocator);
+        result.map(|_| child)
+    }
+
+    // マップ済みのページを全部childにもマップする
+    fn share_pages(
+        &self,
+        child: &AddressSpace,
+        frame_allocator: &mut BuddyFrameAllocator,
+    ) -> Result<(), VmError> {
+        let mut mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        let mut child_mapper = unsafe { address_space::mapper(child.level_4_table()) };
+        for vma in self.vmas.values() {
+            let device = matches!(vma.backing, Backing::Device { .. });
+            for page in vma.pages() {
+                let Ok(frame) = mapper.translate_page(page) else {
+                    continue;
+                };
+                let mut flags = vma.protection.page_flags(self.user);
+                if device {
+                    flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+                } else {
+                    if flags.contains(PageTableFlags::WRITABLE) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                        if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                            flush.flush();
+                        }
+                    }
+                    frame_allocator.share(frame);
+                }
+                unsafe { child_mapper.map_to(page, frame, flags, frame_allocator) }
+                    .map_err(|_| VmError::OutOfMemory)?
+                    .ignore();
+            }
+        }
+        Ok(())
+    }
+
+    // vmaのマップ済みのページを外し、デバイス以外のフレームを解放する
+    fn unmap_pages(&self, vma: &Vma) -> Result<(), VmError> {
+        let mut frame_allocator = frame_allocator(true)?;
+        let mut mapper = unsafe { address_space::mapper(self.level_4_table()) };
+        for page in vma.pages() {
+            let Ok((frame, flush)) = mapper.unmap(page) else {
+                continue;
+            };
+            flush.flush();
+            if !matches!(vma.backing, Backing::Device { .. }) {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    // デバイスのフレームを外してから、ユーザ空間のフレームとページテーブルを全部解放する
+    fn drop(&mut self) {
+        if !self.user {
+            return;
+        }
+        let devices: Vec<Vma> = self
+            .vmas
+            .values()
+            .filter(|vma| matches!(vma.backing, Backing::Device { .. }))
+            .cloned()
+            .collect();
+        for vma in devices {
+            let _ = self.unmap_pages(&vma);
+        }
+        if let Ok(mut frame_allocator) = frame_allocator(true) {
+            unsafe { address_space::free_level_4_table(self.level_4_frame, &mut *frame_allocator) };
+        }
+    }
+}
+
+// waitがfalseのときは、ロックが取れなければBusyを返す
+fn frame_allocator(wait: bool) -> Result<IrqSpinlockGuard<'static, BuddyFrameAllocator>, VmError> {
+    let frame_allocator = FRAME_ALLOCATOR.get().ok_or(VmError::OutOfMemory)?;
+    if wait {
+        Ok(frame_allocator.lock())
+    } else {
+        frame_allocator.try_lock().ok_or(VmError::Busy)
+    }
+}
+
+fn zeroed_frame(wait: bool) -> Result<PhysFrame, VmError> {
+    let frame = frame_allocator(wait)?
+        .allocate_frame()
+        .ok_or(VmError::OutOfMemory)?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE as usize);
+    }
+    Ok(frame)
+}
+
+/// カーネルのサブシステム向けのアドレス空間と、カーネルスタックの領域を作る
+/// 使っていないレベル4エントリを選んでレベル3テーブルを置き、以降に作るプロセスと共有する
+pub fn init() {
+    let level_4_frame = *KERNEL_PAGE_TABLE
+        .get()
+        .expect("kernel page table is not initialized");
+    let level_4_table =
+        unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>() };
+    KERNEL_ADDRESS_SPACE.init_once(|| Spinlock::new(kernel_region(level_4_frame, level_4_table)));
+    let stacks = kernel_region(level_4_frame, level_4_table);
+    STACK_REGION.init_once(|| stacks.range.clone());
+    KERNEL_STACKS.init_once(|| IrqSpinlock::new(stacks));
+}
+
+// 下位半分のうち、ユーザ空間より上で空いているレベル4エントリ1つ分(512GiB)のアドレス空間
+fn kernel_region(level_4_frame: PhysFrame, level_4_table: &mut PageTable) -> AddressSpace {
+    let index = (USER_LEVEL_4_ENTRIES..256)
+        .find(|&index| level_4_table[index].is_unused())
+        .expect("no free level 4 entry for the kernel address space");
+    let level_3_frame = zeroed_frame(true).expect("failed to allocate a level 3 page table");
+    level_4_table[index].set_frame(
+        level_3_frame,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    );
+
+    let start = (index as u64) << 39;
+    AddressSpace {
+        level_4_frame,
+        range: start..start + (1 << 39),
+        mmap_base: start,
+        user: false,
+        vmas: BTreeMap::new(),
+    }
+}
+
+/// sizeバイトのカーネルスタックを確保し、その上端を返す
+/// 使っている途中でページフォルトを起こさないように、フレームは全部先に割り当てる
+pub fn alloc_stack(size: u64) -> Result<VirtAddr, VmError> {
+    if size == 0 || size > STACK_SLOT_SIZE - PAGE_SIZE {
+        return Err(VmError::InvalidArgument);
+    }
+    let mut stacks = KERNEL_STACKS
+        .get()
+        .expect("kernel address space is not initialized")
+        .lock();
+    // 空いているスロットのガードページの上から置く
+    let region = stacks.range.clone();
+    let start = region
+        .step_by(STACK_SLOT_SIZE as usize)
+        .map(|slot| slot + PAGE_SIZE)
+        .find(|start| !stacks.vmas.contains_key(start))
+        .ok_or(VmError::NoSpace)?;
+    let start = stacks.mmap(
+        Some(VirtAddr::new(start)),
+        size,
+        Protection::READ | Protection::WRITE,
+        Backing::Anonymous,
+    )?;
+    if let Err(error) = stacks.populate(start, size, Access::Write) {
+        stacks.munmap(start, size)?;
+        return Err(error);
+    }
+    Ok(start + size)
+}
+
+/// alloc_stackで確保したスタックを解放する
+pub fn free_stack(top: VirtAddr, size: u64) {
+    let mut stacks = KERNEL_STACKS
+        .get()
+        .expect("kernel address space is not initialized")
+        .lock();
+    stacks
+        .munmap(top - size, size)
+        .expect("freeing a kernel stack that is not allocated");
+}
+
+/// addrがカーネルスタックの下のガードページの中か
+/// ページフォルトハンドラから呼ばれるので、ロックは取らない
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    STACK_REGION.get().is_some_and(|region| {
+        region.contains(&addr.as_u64())
+            && (addr.as_u64() - region.start) % STACK_SLOT_SIZE < PAGE_SIZE
+    })
+}
+
+fn kernel_address_space() -> &'static Spinlock<AddressSpace> {
+    KERNEL_ADDRESS_SPACE
+        .get()
+        .expect("kernel address space is not initialized")
+}
+
+/// カーネルのアドレス空間にlenバイトの領域を登録する
+pub fn kernel_mmap(
+    len: u64,
+    protection: Protection,
+    backing: Backing,
+) -> Result<VirtAddr, VmError> {
+    kernel_address_space()
+        .lock()
+        .mmap(None, len, protection, backing)
+}
+
+pub fn kernel_munmap(addr: VirtAddr, len: u64) -> Result<(), VmError> {
+    kernel_address_space().lock().munmap(addr, len)
+}
+
+pub fn kernel_mprotect(addr: VirtAddr, len: u64, protection: Protection) -> Result<(), VmError> {
+    kernel_address_space()
+        .lock()
+        .mprotect(addr, len, protection)
+}
+
+/// ページフォルトを起こしたアドレスを含むアドレス空間で処理する
+/// 割り込みハンドラから呼ばれるので、ロックは待たずにtry_lockで取る
+/// Busyのときは、ロックを持っているのが別のプロセスならフォルトした命令をやり直せばよい
+pub fn handle_page_fault(addr: VirtAddr, access: Access) -> Result<(), VmError> {
+    if addr.as_u64() >= USER_SPACE_END {
+        return KERNEL_ADDRESS_SPACE
+            .get()
+            .ok_or(VmError::NotMapped)?
+            .try_lock()
+            .ok_or(VmError::Busy)?
+            .handle_fault(addr, access);
+    }
+    let address_space = SCHEDULER
+        .get()
+        .ok_or(VmError::NotMapped)?
+        .try_lock()
+        .ok_or(VmError::Busy)?
+        .current_address_space()
+        .ok_or(VmError::NotMapped)?;
+    let mut address_space = address_space.try_lock().ok_or(VmError::Busy)?;
+    address_space.handle_fault(addr, access)
+}
+
+/// カーネルのアドレス空間の領域の一覧
+pub fn kernel_vmas() -> Vec<Vma> {
+    kernel_address_space().lock().vmas().cloned().collect()
+}
+
+impl core::fmt::Display for Protection {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let flag = |protection, c| if self.contains(protection) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(Self::READ, 'r'),
+            flag(Self::WRITE, 'w'),
+            flag(Self::EXECUTE, 'x')
+        )
+    }
+}
@@ -1,16 +1,46 @@
-use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{format, string::ToString, sync::Arc, vec::Vec};
 use spin::Mutex;
-use x86_64::{structures::paging::PageTable, VirtAddr};
+use stack::KernelStack;
+use x86_64::{registers::control::Cr3, structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+use crate::{
+    fs::file::FileDescriptorTable,
+    gdt,
+    memory::{
+        vmm::{AddressSpace, VmError},
+        KERNEL_PAGE_TABLE,
+    },
+    println,
+    sync::semaphore::Semaphore,
+    task::shell,
+    time::{ticks_to_ms, Instant},
+};
+use scheduler::policy::SchedEntity;
 
+pub mod exec;
 pub mod scheduler;
+pub mod stack;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProcessId(u64);
 
-#[derive(Debug, PartialEq, Eq)]
+impl ProcessId {
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
     Ready,
-    Ruuing,
+    Running,
     Blocked,
     Terminated,
 }
@@ -19,10 +49,223 @@ pub struct Process {
     id: ProcessId,
     state: Mutex<ProcessState>,
     parent_id: Option<ProcessId>,
-    page_table: Mutex<Option<&'static mut PageTable>>,
+    // ブート時のスレッドは自前のアドレス空間を持たない
+    address_space: Option<Arc<Mutex<AddressSpace>>>,
     stack_top: VirtAddr,
     context: Mutex<ProcessContext>,
     children: Mutex<Vec<ProcessId>>,
+    // 起動時のスレッドは自前のスタックを持たない
+    kernel_stack: Option<KernelStack>,
+    // sleepしているプロセスが起きる時刻
+    wake_at: Mutex<Option<Instant>>,
+    files: Mutex<FileDescriptorTable>,
+    // 終了したときのステータス。親がwaitで受け取るまでTerminatedのまま残る
+    exit_status: Mutex<i32>,
+    // 子が終了するたびにreleaseされる。waitで子の終了を眠って待つのに使う
+    child_exited: Arc<Semaphore>,
+    // niceとCPU時間
+    sched: Mutex<SchedEntity>,
+}
+
+impl Process {
+    // ブートしたときから動いているカーネルの実行の流れをプロセスとして扱う
+    // ブート時のページテーブルは所有しないのでaddress_spaceはNoneのまま
+    fn boot() -> Self {
+        let context = ProcessContext {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            ..Default::default()
+        };
+        Self {
+            id: ProcessId::new(),
+            state: Mutex::new(ProcessState::Running),
+            parent_id: None,
+            address_space: None,
+            stack_top: VirtAddr::zero(),
+            context: Mutex::new(context),
+            children: Mutex::new(Vec::new()),
+            kernel_stack: None,
+            wake_at: Mutex::new(None),
+            files: Mutex::new(FileDescriptorTable::new()),
+            exit_status: Mutex::new(0),
+            child_exited: Arc::new(Semaphore::new(0)),
+            sched: Mutex::new(SchedEntity::default()),
+        }
+    }
+
+    // カーネルスレッド。ユーザ空間は使わないので、自前のアドレス空間は作らずにカーネルのページテーブルで動かす
+    fn new(entry_point: u64, parent_id: Option<ProcessId>) -> Result<Self, VmError> {
+        let kernel_stack = KernelStack::try_new()?;
+        // エントリ関数がreturnしたときにprocess_exitへ戻るようにしておく
+        let rsp = kernel_stack.top() - 8u64;
+        unsafe {
+            rsp.as_mut_ptr::<u64>()
+                .write(scheduler::process_exit as usize as u64)
+        };
+
+        let mut context = ProcessContext::new_kernel(entry_point, rsp);
+        context.cr3 = KERNEL_PAGE_TABLE
+            .get()
+            .expect("kernel page table is not initialized")
+            .start_address()
+            .as_u64();
+        Ok(Self::with_context(
+            context,
+            kernel_stack,
+            None,
+            FileDescriptorTable::new(),
+            parent_id,
+        ))
+    }
+
+    // Ring 3で動くプロセス
+    // コードとユーザスタックの領域は呼び出し側がaddress_spaceに登録しておく
+    fn new_user(
+        entry_point: u64,
+        user_stack_top: VirtAddr,
+        address_space: AddressSpace,
+        files: FileDescriptorTable,
+        parent_id: Option<ProcessId>,
+    ) -> Self {
+        let context = ProcessContext::new_user(entry_point, user_stack_top);
+        Self::with_context(
+            context,
+            KernelStack::new(),
+            Some(address_space),
+            files,
+            parent_id,
+        )
+    }
+
+    // システムコールに入ったときのframeから、forkの戻り値が0になって再開する子プロセス
+    fn fork(
+        frame: &TrapFrame,
+        address_space: AddressSpace,
+        files: FileDescriptorTable,
+        parent_id: ProcessId,
+    ) -> Self {
+        let mut context = ProcessContext::new_user(frame.rip, VirtAddr::new(frame.rsp));
+        context.save(frame);
+        context.rax = 0;
+        Self::with_context(
+            context,
+            KernelStack::new(),
+            Some(address_space),
+            files,
+            Some(parent_id),
+        )
+    }
+
+    // address_spaceがNoneなら、contextのcr3をそのまま使う
+    fn with_context(
+        mut context: ProcessContext,
+        kernel_stack: KernelStack,
+        address_space: Option<AddressSpace>,
+        files: FileDescriptorTable,
+        parent_id: Option<ProcessId>,
+    ) -> Self {
+        if let Some(address_space) = &address_space {
+            context.cr3 = address_space.level_4_frame().start_address().as_u64();
+        }
+        Self {
+            id: ProcessId::new(),
+            state: Mutex::new(ProcessState::Ready),
+            parent_id,
+            address_space: address_space.map(|address_space| Arc::new(Mutex::new(address_space))),
+            stack_top: kernel_stack.top(),
+            context: Mutex::new(context),
+            children: Mutex::new(Vec::new()),
+            kernel_stack: Some(kernel_stack),
+            wake_at: Mutex::new(None),
+            files: Mutex::new(files),
+            exit_status: Mutex::new(0),
+            child_exited: Arc::new(Semaphore::new(0)),
+            sched: Mutex::new(SchedEntity::default()),
+        }
+    }
+}
+
+/// psで表示するプロセスの情報
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub id: ProcessId,
+    pub parent_id: Option<ProcessId>,
+    pub state: ProcessState,
+    pub user: bool,
+    pub nice: i8,
+    pub cpu_ticks: u64,
+}
+
+impl Process {
+    fn info(&self) -> ProcessInfo {
+        let sched = *self.sched.lock();
+        ProcessInfo {
+            id: self.id,
+            parent_id: self.parent_id,
+            state: *self.state.lock(),
+            // Ring 3のコードセグメントならユーザプロセス
+            user: self.context.lock().cs & 3 == 3,
+            nice: sched.nice,
+            cpu_ticks: sched.cpu_ticks,
+        }
+    }
+}
+
+pub fn register_commands() {
+    shell::register("ps", "list processes", |_args| {
+        let Some(scheduler) = scheduler::SCHEDULER.get() else {
+            return;
+        };
+        // 途中で切り替わっても食い違わないように、1回のロックでまとめて取る
+        let (current, processes, policy_name) = {
+            let scheduler = scheduler.lock();
+            (
+                scheduler.current(),
+                scheduler.processes(),
+                scheduler.policy_name(),
+            )
+        };
+        println!("scheduler: {}", policy_name);
+        println!(
+            "{:>4} {:>5}  {:<10} {:>3} {:>8} MODE",
+            "PID", "PPID", "STATE", "NI", "TIME(ms)"
+        );
+        for process in processes {
+            let parent = process
+                .parent_id
+                .map_or("-".to_string(), |id| id.as_u64().to_string());
+            let marker = if Some(process.id) == current { "*" } else { "" };
+            println!(
+                "{:>4} {:>5}  {:<10} {:>3} {:>8} {}{}",
+                process.id.as_u64(),
+                parent,
+                format!("{:?}", process.state),
+                process.nice,
+                ticks_to_ms(process.cpu_ticks),
+                if process.user { "user" } else { "kernel" },
+                marker
+            );
+        }
+    });
+    shell::register(
+        "renice",
+        "change the nice value of a process (renice <nice> <pid>)",
+        |args| {
+            let (Some(Ok(nice)), Some(Ok(pid))) = (
+                args.get(1).map(|arg| arg.parse::<i8>()),
+                args.get(2).map(|arg| arg.parse::<u64>()),
+            ) else {
+                println!("usage: renice <nice> <pid>");
+                return;
+            };
+            let Some(scheduler) = scheduler::SCHEDULER.get() else {
+                return;
+            };
+            match scheduler.lock().set_nice(pid, nice) {
+                Some(nice) => println!("process {}: nice {}", pid, nice),
+                None => println!("renice: no process {}", pid),
+            }
+        },
+    );
 }
 
 #[derive(Debug, Default)]
@@ -58,3 +301,122 @@ pub struct ProcessContext {
     // CR3 (ページテーブルベースアドレス)
     pub cr3: u64,
 }
+
+impl ProcessContext {
+    // 割り込み許可フラグ(IF)と常に1のビット
+    const INITIAL_RFLAGS: u64 = 0x202;
+
+    fn new_kernel(entry_point: u64, rsp: VirtAddr) -> Self {
+        let selectors = gdt::selectors();
+        Self {
+            rip: entry_point,
+            rsp: rsp.as_u64(),
+            rflags: Self::INITIAL_RFLAGS,
+            cs: selectors.code_selector.0 as u64,
+            ss: selectors.data_selector.0 as u64,
+            ds: selectors.data_selector.0 as u64,
+            es: selectors.data_selector.0 as u64,
+            ..Default::default()
+        }
+    }
+
+    // iretqでRing 3に入るためのコンテキスト
+    // セレクタのRPLは3になっている
+    fn new_user(entry_point: u64, user_stack_top: VirtAddr) -> Self {
+        let selectors = gdt::selectors();
+        Self {
+            rip: entry_point,
+            rsp: user_stack_top.as_u64(),
+            rflags: Self::INITIAL_RFLAGS,
+            cs: selectors.user_code_selector.0 as u64,
+            ss: selectors.user_data_selector.0 as u64,
+            ds: selectors.user_data_selector.0 as u64,
+            es: selectors.user_data_selector.0 as u64,
+            ..Default::default()
+        }
+    }
+
+    /// 割り込みエントリが積んだレジスタを退避する
+    pub fn save(&mut self, frame: &TrapFrame) {
+        self.rax = frame.rax;
+        self.rbx = frame.rbx;
+        self.rcx = frame.rcx;
+        self.rdx = frame.rdx;
+        self.rsi = frame.rsi;
+        self.rdi = frame.rdi;
+        self.rbp = frame.rbp;
+        self.rsp = frame.rsp;
+        self.r8 = frame.r8;
+        self.r9 = frame.r9;
+        self.r10 = frame.r10;
+        self.r11 = frame.r11;
+        self.r12 = frame.r12;
+        self.r13 = frame.r13;
+        self.r14 = frame.r14;
+        self.r15 = frame.r15;
+        self.rip = frame.rip;
+        self.rflags = frame.rflags;
+        self.cs = frame.cs;
+        self.ss = frame.ss;
+    }
+
+    /// このプロセスのレベル4テーブルをCR3にロードする
+    pub unsafe fn switch_address_space(&self) {
+        let (current, flags) = Cr3::read();
+        let next = PhysFrame::containing_address(PhysAddr::new(self.cr3));
+        if self.cr3 != 0 && current != next {
+            Cr3::write(next, flags);
+        }
+    }
+
+    /// iretqで復帰したときにこのコンテキストから実行が再開されるようにする
+    pub fn restore(&self, frame: &mut TrapFrame) {
+        frame.rax = self.rax;
+        frame.rbx = self.rbx;
+        frame.rcx = self.rcx;
+        frame.rdx = self.rdx;
+        frame.rsi = self.rsi;
+        frame.rdi = self.rdi;
+        frame.rbp = self.rbp;
+        frame.rsp = self.rsp;
+        frame.r8 = self.r8;
+        frame.r9 = self.r9;
+        frame.r10 = self.r10;
+        frame.r11 = self.r11;
+        frame.r12 = self.r12;
+        frame.r13 = self.r13;
+        frame.r14 = self.r14;
+        frame.r15 = self.r15;
+        frame.rip = self.rip;
+        frame.rflags = self.rflags;
+        frame.cs = self.cs;
+        frame.ss = self.ss;
+    }
+}
+
+/// 割り込みエントリがスタックに積むレジスタの並び
+/// 汎用レジスタの後ろにCPUが積んだ割り込みスタックフレームが続く
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
@@ -0,0 +1,289 @@
+//! ELFの実行ファイルから新しいユーザプロセスを作る
+
+use alloc::{vec, vec::Vec};
+use x86_64::VirtAddr;
+
+use crate::{
+    elf::{Elf, ElfError, ElfType, ProgramHeader, SegmentType},
+    fs::{
+        file::{FileDescriptorTable, OpenFile, OpenFlags},
+        vfs::{self, FileType},
+        FsError,
+    },
+    memory::{
+        address_space::{USER_SPACE_START, USER_STACK_SIZE, USER_STACK_TOP},
+        vmm::{AddressSpace, Backing, Protection, VmError},
+    },
+};
+
+use super::{
+    scheduler::{self, SCHEDULER},
+    ProcessId,
+};
+
+// 位置独立実行ファイルをロードするアドレス
+pub const PIE_LOAD_BASE: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 4096;
+
+// 補助ベクタのタイプ
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum ExecError {
+    Fs(FsError),
+    Elf(ElfError),
+    SegmentOutOfRange,
+    ArgumentsTooLarge,
+    Vm(VmError),
+}
+
+impl From<FsError> for ExecError {
+    fn from(error: FsError) -> Self {
+        ExecError::Fs(error)
+    }
+}
+
+impl From<ElfError> for ExecError {
+    fn from(error: ElfError) -> Self {
+        ExecError::Elf(error)
+    }
+}
+
+impl From<VmError> for ExecError {
+    fn from(error: VmError) -> Self {
+        ExecError::Vm(error)
+    }
+}
+
+/// pathの実行ファイルを読み込んでユーザプロセスを作る
+/// 標準入出力はコンソールにつなぐ
+pub fn spawn_file(
+    path: &str,
+    argv: &[&str],
+    envp: &[&str],
+    parent_id: Option<ProcessId>,
+) -> Result<ProcessId, ExecError> {
+    let dentry = vfs::resolve(path)?;
+    let inode = dentry.inode();
+    if inode.metadata().file_type != FileType::File {
+        return Err(FsError::IsADirectory.into());
+    }
+    let files = FileDescriptorTable::with_stdio()?;
+    if let Some(image) = inode.mapped_data() {
+        return spawn(image, argv, envp, files, parent_id);
+    }
+
+    let file = OpenFile::open(path, OpenFlags::READ_ONLY)?;
+    let mut image = vec![0; file.stat().size as usize];
+    let mut read = 0;
+    while read < image.len() {
+        match file.read(&mut image[read..])? {
+            0 => break,
+            len => read += len,
+        }
+    }
+    image.truncate(read);
+    spawn(&image, argv, envp, files, parent_id)
+}
+
+/// ELFイメージを新しいアドレス空間にロードし、ユーザプロセスとしてReadyQueueに入れる
+pub fn spawn(
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    files: FileDescriptorTable,
+    parent_id: Option<ProcessId>,
+) -> Result<ProcessId, ExecError> {
+    let elf = Elf::parse(image)?;
+    let base = match elf.elf_type() {
+        ElfType::Executable => 0,
+        ElfType::SharedObject => PIE_LOAD_BASE,
+    };
+
+    // 失敗したときはaddress_spaceをdropすればマップしたフレームもまとめて解放される
+    let mut address_space = AddressSpace::new_user()?;
+    load(&elf, base, &mut address_space)?;
+    setup_stack(&elf, base, argv, envp, &mut address_space)?;
+
+    let user_stack_top = initial_stack_pointer(argv, envp);
+    scheduler::reap();
+    let scheduler = SCHEDULER.get().expect("scheduler is not initialized");
+    Ok(scheduler.lock().create_user_process(
+        base + elf.entry(),
+        VirtAddr::new(user_stack_top),
+        address_space,
+        files,
+        parent_id,
+    ))
+}
+
+// PT_LOADのセグメントの領域を登録し、ファイルの内容をコピーする
+fn load(elf: &Elf, base: u64, address_space: &mut AddressSpace) -> Result<(), ExecError> {
+    let user_image_end = USER_STACK_TOP - USER_STACK_SIZE;
+    // 同じページにかかるセグメントは1つの領域にまとめ、両方の権限を許す
+    let mut regions: Vec<(u64, u64, Protection)> = Vec::new();
+    for header in elf.program_headers() {
+        if !header.is(SegmentType::Load) || header.memsz == 0 {
+            continue;
+        }
+        let start = base
+            .checked_add(header.vaddr)
+            .ok_or(ExecError::SegmentOutOfRange)?;
+        let end = start
+            .checked_add(header.memsz)
+            .ok_or(ExecError::SegmentOutOfRange)?;
+        if start < USER_SPACE_START || end > user_image_end || header.filesz > header.memsz {
+            return Err(ExecError::SegmentOutOfRange);
+        }
+
+        regions.push((
+            start & !(PAGE_SIZE - 1),
+            end.next_multiple_of(PAGE_SIZE),
+            segment_protection(&header),
+        ));
+    }
+    regions.sort_unstable_by_key(|&(start, _, _)| start);
+    let mut merged: Vec<(u64, u64, Protection)> = Vec::new();
+    for (start, end, protection) in regions {
+        match merged.last_mut() {
+            Some(last) if start < last.1 => {
+                last.1 = last.1.max(end);
+                last.2 = last.2 | protection;
+            }
+            _ => merged.push((start, end, protection)),
+        }
+    }
+    for (start, end, protection) in merged {
+        address_space.mmap(
+            Some(VirtAddr::new(start)),
+            end - start,
+            protection,
+            Backing::Anonymous,
+        )?;
+    }
+
+    // filesz以降(.bss)はゼロ埋めしたフレームのまま残す
+    for header in elf.program_headers() {
+        if !header.is(SegmentType::Load) || header.filesz == 0 {
+            continue;
+        }
+        address_space.write(
+            VirtAddr::new(base + header.vaddr),
+            &elf.data()[header.file_range()],
+        )?;
+    }
+
+    if base != 0 {
+        for relocation in elf.relocations()? {
+            let value = base.wrapping_add(relocation.addend);
+            address_space
+                .write(
+                    VirtAddr::new(base + relocation.offset),
+                    &value.to_le_bytes(),
+                )
+                .map_err(|_| ExecError::SegmentOutOfRange)?;
+        }
+    }
+    Ok(())
+}
+
+fn segment_protection(header: &ProgramHeader) -> Protection {
+    let mut protection = Protection::READ;
+    if header.is_writable() {
+        protection = protection | Protection::WRITE;
+    }
+    if header.is_executable() {
+        protection = protection | Protection::EXECUTE;
+    }
+    protection
+}
+
+// System V ABIに従った初期スタック
+//
+//   文字列(argv, envp)
+//   (16バイト境界に揃えるためのパディング)
+//   補助ベクタ(AT_NULLで終わる)
+//   envp[] (NULLで終わる)
+//   argv[] (NULLで終わる)
+//   argc                    <- rsp
+fn setup_stack(
+    elf: &Elf,
+    base: u64,
+    argv: &[&str],
+    envp: &[&str],
+    address_space: &mut AddressSpace,
+) -> Result<(), ExecError> {
+    // 引数を書き込むページ以外は、触ったときに割り当てる
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space.mmap(
+        Some(VirtAddr::new(stack_bottom)),
+        USER_STACK_SIZE,
+        Protection::READ | Protection::WRITE,
+        Backing::Anonymous,
+    )?;
+
+    let (strings_start, strings) = pack_strings(argv, envp);
+    let rsp = initial_stack_pointer(argv, envp);
+    // スタックの半分までを引数に使えるようにする
+    if USER_STACK_TOP - rsp > USER_STACK_SIZE / 2 {
+        return Err(ExecError::ArgumentsTooLarge);
+    }
+    address_space.write(VirtAddr::new(strings_start), &strings)?;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    let mut string_addr = strings_start;
+    for arg in argv {
+        words.push(string_addr);
+        string_addr += arg.len() as u64 + 1;
+    }
+    words.push(0);
+    for env in envp {
+        words.push(string_addr);
+        string_addr += env.len() as u64 + 1;
+    }
+    words.push(0);
+
+    let phdr = elf
+        .offset_to_vaddr(elf.phoff())
+        .map_or(0, |vaddr| base + vaddr);
+    for (key, value) in [
+        (AT_PHDR, phdr),
+        (AT_PHENT, 56),
+        (AT_PHNUM, elf.phnum() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, base + elf.entry()),
+        (AT_NULL, 0),
+    ] {
+        words.push(key);
+        words.push(value);
+    }
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(rsp), &bytes)?;
+    Ok(())
+}
+
+// argvとenvpの文字列をNUL終端で並べ、スタックの一番上に置いたときの先頭アドレスと一緒に返す
+fn pack_strings(argv: &[&str], envp: &[&str]) -> (u64, Vec<u8>) {
+    let mut strings = Vec::new();
+    for s in argv.iter().chain(envp) {
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    (USER_STACK_TOP - strings.len() as u64, strings)
+}
+
+// argcが置かれるアドレス。16バイト境界に揃える
+fn initial_stack_pointer(argv: &[&str], envp: &[&str]) -> u64 {
+    let strings_len: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    // argc + argv + NULL + envp + NULL + 補助ベクタ6組
+    let words = 1 + argv.len() as u64 + 1 + envp.len() as u64 + 1 + 6 * 2;
+    let strings_start = (USER_STACK_TOP - strings_len) & !0xf;
+    (strings_start - words * 8) & !0xf
+}
@@ -1,46 +1,517 @@
-use alloc::collections::{btree_map::BTreeMap, vec_deque::VecDeque};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
-use spinning_top::Spinlock;
+use x86_64::VirtAddr;
+
+use crate::{
+    fs::file::FileDescriptorTable,
+    gdt,
+    memory::vmm::{AddressSpace, VmError},
+    sync::{irq_spinlock::IrqSpinlock, semaphore::Semaphore},
+    syscall,
+    time::{Duration, Instant},
+};
+
+use super::{Process, ProcessId, ProcessInfo, ProcessState, TrapFrame};
+use policy::{SchedulingPolicy, NICE_MAX, NICE_MIN};
 
-use crate::println;
+mod fair;
+pub mod policy;
+mod priority;
+mod round_robin;
 
-use super::{Process, ProcessId};
+pub static SCHEDULER: OnceCell<IrqSpinlock<Scheduler>> = OnceCell::uninit();
 
-pub static SCHEDULER: OnceCell<Spinlock<Scheduler>> = OnceCell::uninit();
+/// wait_childの結果
+pub enum WaitResult {
+    // 終了した子のIDと終了ステータス
+    // 回収した子はSCHEDULERのロックを手放してからdropする
+    Exited {
+        id: ProcessId,
+        status: i32,
+        process: Box<Process>,
+    },
+    // 待つ対象の子がいない
+    NoChildren,
+    // 子はいるがまだ終了していない
+    // 子が終了するとこのセマフォがreleaseされるので、acquireして待ってから調べ直す
+    Pending(Arc<Semaphore>),
+}
 
 pub struct Scheduler {
-    processes: Mutex<BTreeMap<ProcessId, Process>>,
-    ready_queue: Mutex<VecDeque<ProcessId>>,
+    // スラブアロケータのprocessキャッシュから確保されるように、1つずつBoxに入れる
+    processes: Mutex<BTreeMap<ProcessId, Box<Process>>>,
+    // 実行可能なプロセスのうち、どれを次に動かすかを決める
+    policy: Mutex<Box<dyn SchedulingPolicy>>,
     current: Mutex<Option<ProcessId>>,
+    // システムコールの終わりにプロセスを切り替える必要があるか
+    need_resched: AtomicBool,
+    // 実行中のプロセスが切り替わってから続けて実行したティック数
+    ran_ticks: AtomicU64,
 }
 
 impl Scheduler {
-    pub fn new() -> Self {
+    pub fn new(policy: Box<dyn SchedulingPolicy>) -> Self {
+        let boot = Process::boot();
+        let boot_id = boot.id;
+        let mut processes = BTreeMap::new();
+        processes.insert(boot_id, Box::new(boot));
         Self {
-            processes: Mutex::new(BTreeMap::new()),
-            ready_queue: Mutex::new(VecDeque::new()),
-            current: Mutex::new(None),
+            processes: Mutex::new(processes),
+            policy: Mutex::new(policy),
+            current: Mutex::new(Some(boot_id)),
+            need_resched: AtomicBool::new(false),
+            ran_ticks: AtomicU64::new(0),
         }
     }
 
+    pub fn policy_name(&self) -> &'static str {
+        self.policy.lock().name()
+    }
+
     // 新しいプロセスを作成し、ReadyQueueに追加
-    pub fn create_process(&self, entry_point: u64, parent_id: Option<ProcessId>) -> ProcessId {
-        // プロセス作成ロジック
-        todo!()
+    pub fn create_process(
+        &self,
+        entry_point: u64,
+        parent_id: Option<ProcessId>,
+    ) -> Result<ProcessId, VmError> {
+        Ok(self.add_process(Process::new(entry_point, parent_id)?))
+    }
+
+    // Ring 3で動くプロセスを作成する
+    // 最初にスケジュールされたときのiretqでユーザモードに入る
+    pub fn create_user_process(
+        &self,
+        entry_point: u64,
+        user_stack_top: VirtAddr,
+        address_space: AddressSpace,
+        files: FileDescriptorTable,
+        parent_id: Option<ProcessId>,
+    ) -> ProcessId {
+        self.add_process(Process::new_user(
+            entry_point,
+            user_stack_top,
+            address_space,
+            files,
+            parent_id,
+        ))
+    }
+
+    // 実行中のプロセスをforkした子プロセスを作成する
+    // frameはシステムコールに入ったときのユーザのレジスタで、子はforkから0を返して再開する
+    pub fn create_forked_process(
+        &self,
+        frame: &TrapFrame,
+        address_space: AddressSpace,
+        files: FileDescriptorTable,
+    ) -> Option<ProcessId> {
+        let parent_id = self.current()?;
+        Some(self.add_process(Process::fork(frame, address_space, files, parent_id)))
+    }
+
+    fn add_process(&self, process: Process) -> ProcessId {
+        let id = process.id;
+
+        let mut processes = self.processes.lock();
+        if let Some(parent) = process
+            .parent_id
+            .and_then(|parent_id| processes.get(&parent_id))
+        {
+            parent.children.lock().push(id);
+            // niceは親から引き継ぐ
+            process.sched.lock().nice = parent.sched.lock().nice;
+        }
+        let mut policy = self.policy.lock();
+        // 割り込みコンテキストでenqueueしても再割り当てが起きないように
+        // 全プロセス分の容量を確保しておく
+        policy.reserve(processes.len() + 1);
+        policy.enqueue(id, &mut process.sched.lock());
+        processes.insert(id, Box::new(process));
+        id
+    }
+
+    // 実行可能になったプロセスをポリシーのキューに入れる
+    fn enqueue(&self, process: &Process) {
+        self.policy
+            .lock()
+            .enqueue(process.id, &mut process.sched.lock());
     }
 
     // 次のプロセスを選択
-    pub fn schedule(&self) -> Option<&Process> {
-        // スケジューリングロジック
-        todo!()
+    pub fn schedule(&self) -> Option<ProcessId> {
+        let processes = self.processes.lock();
+        let mut policy = self.policy.lock();
+        while let Some(id) = policy.pick_next() {
+            let is_ready = processes
+                .get(&id)
+                .is_some_and(|process| *process.state.lock() == ProcessState::Ready);
+            if is_ready {
+                return Some(id);
+            }
+        }
+        None
     }
 
-    // Context Switchを実行
-    pub fn context_switch(&self) {
-        // 現在のプロセスのコンテキストを保存
+    // Context Switchを実行し、切り替えが起きたかを返す
+    // 割り込みコンテキストから呼ばれるため、ヒープ割り当てをしてはいけない
+    pub fn context_switch(&self, frame: &mut TrapFrame) -> bool {
         // 次のプロセスを選択
+        let Some(next_id) = self.schedule() else {
+            return false;
+        };
+
+        let processes = self.processes.lock();
+        let mut current = self.current.lock();
+
+        // 現在のプロセスのコンテキストを保存
+        if let Some(prev) = current.and_then(|id| processes.get(&id)) {
+            prev.context.lock().save(frame);
+            let mut state = prev.state.lock();
+            if *state == ProcessState::Running {
+                *state = ProcessState::Ready;
+                self.enqueue(prev);
+            }
+        }
+
         // 新しいプロセスのコンテキストを復元
-        println!("hi");
+        let next = &processes[&next_id];
+        *next.state.lock() = ProcessState::Running;
+        let context = next.context.lock();
+        context.restore(frame);
+        // カーネル部分は全アドレス空間で共有しているので、ここで切り替えてもそのまま実行を続けられる
+        unsafe { context.switch_address_space() };
+        if let Some(kernel_stack) = &next.kernel_stack {
+            gdt::set_kernel_stack(kernel_stack.top());
+            syscall::set_kernel_stack(kernel_stack.top());
+        }
+        *current = Some(next_id);
+        self.ran_ticks.store(0, Ordering::Relaxed);
+        true
+    }
+
+    // タイマ割り込みごとに呼ばれ、実行中のプロセスに1ティック分のCPU時間を付ける
+    // 実行中のプロセスを切り替えるべきならtrueを返す
+    pub fn tick(&self) -> bool {
+        let processes = self.processes.lock();
+        let Some(process) = self.current.lock().and_then(|id| processes.get(&id)) else {
+            return true;
+        };
+        let mut entity = process.sched.lock();
+        entity.cpu_ticks += 1;
+        let mut policy = self.policy.lock();
+        policy.charge(&mut entity);
+        let ran = self.ran_ticks.fetch_add(1, Ordering::Relaxed) + 1;
+        policy.should_preempt(&entity, ran)
+    }
+
+    // pidのプロセスのniceを変える。次にキューに入るときから効く
+    pub fn set_nice(&self, pid: u64, nice: i8) -> Option<i8> {
+        let processes = self.processes.lock();
+        let process = processes
+            .values()
+            .find(|process| process.id.as_u64() == pid)?;
+        let nice = nice.clamp(NICE_MIN, NICE_MAX);
+        process.sched.lock().nice = nice;
+        Some(nice)
+    }
+
+    // 実行中のプロセスのniceをincrementだけ変え、新しい値を返す
+    pub fn nice_current(&self, increment: i64) -> Option<i8> {
+        let processes = self.processes.lock();
+        let process = self.current.lock().and_then(|id| processes.get(&id))?;
+        let mut sched = process.sched.lock();
+        sched.nice = (sched.nice as i64 + increment).clamp(NICE_MIN as i64, NICE_MAX as i64) as i8;
+        Some(sched.nice)
+    }
+
+    pub fn current(&self) -> Option<ProcessId> {
+        *self.current.lock()
+    }
+
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        self.processes
+            .lock()
+            .values()
+            .map(|process| process.info())
+            .collect()
+    }
+
+    // 実行中のプロセスのファイルディスクリプタテーブルを操作する
+    pub fn with_current_files<R>(
+        &self,
+        f: impl FnOnce(&mut FileDescriptorTable) -> R,
+    ) -> Option<R> {
+        let processes = self.processes.lock();
+        let process = self.current.lock().and_then(|id| processes.get(&id))?;
+        let mut files = process.files.lock();
+        Some(f(&mut files))
+    }
+
+    // 実行中のプロセスのアドレス空間
+    pub fn current_address_space(&self) -> Option<Arc<Mutex<AddressSpace>>> {
+        let processes = self.processes.lock();
+        let process = self.current.lock().and_then(|id| processes.get(&id))?;
+        process.address_space.clone()
+    }
+
+    // pidのプロセスのアドレス空間
+    pub fn address_space(&self, pid: u64) -> Option<Arc<Mutex<AddressSpace>>> {
+        let processes = self.processes.lock();
+        let process = processes
+            .values()
+            .find(|process| process.id.as_u64() == pid)?;
+        process.address_space.clone()
+    }
+
+    // addrがカーネルスタックのガードページの中にあるプロセス
+    // ページフォルトハンドラから呼ばれるので、ロックは待たない
+    pub fn stack_owner(&self, addr: VirtAddr) -> Option<ProcessId> {
+        self.processes
+            .try_lock()?
+            .values()
+            .find(|process| {
+                process
+                    .kernel_stack
+                    .as_ref()
+                    .is_some_and(|stack| stack.guard_contains(addr))
+            })
+            .map(|process| process.id)
+    }
+
+    // 実行中のプロセスを終了状態にし、waitで待っている親がいれば起こす
+    // 実際にCPUを手放すのは次のタイマ割り込みかシステムコールの終わり
+    // 親がいれば、子の終了を知らせる親のセマフォを返す
+    // releaseすると親を起こすためにSCHEDULERのロックを取るので、ここではreleaseしない
+    fn exit_current(&self, status: i32) -> Option<Arc<Semaphore>> {
+        let processes = self.processes.lock();
+        let process = self.current.lock().and_then(|id| processes.get(&id));
+        let child_exited = process.and_then(|process| {
+            *process.exit_status.lock() = status;
+            *process.state.lock() = ProcessState::Terminated;
+            let parent = processes.get(&process.parent_id?)?;
+            Some(parent.child_exited.clone())
+        });
+        self.request_reschedule();
+        child_exited
+    }
+
+    // 実行中のプロセスの子のうち、終了したものを1つ回収して終了ステータスを返す
+    // targetを指定したときはそのIDの子だけを待つ
+    // まだ終了した子がいなければ、子の終了を待つためのセマフォを返す
+    pub fn wait_child(&self, target: Option<u64>) -> WaitResult {
+        let mut processes = self.processes.lock();
+        let Some(parent_id) = *self.current.lock() else {
+            return WaitResult::NoChildren;
+        };
+        let Some(parent) = processes.get(&parent_id) else {
+            return WaitResult::NoChildren;
+        };
+        let mut children = parent.children.lock();
+        let mut waitable = children
+            .iter()
+            .copied()
+            .filter(|id| target.is_none_or(|target| id.as_u64() == target))
+            .peekable();
+        if waitable.peek().is_none() {
+            return WaitResult::NoChildren;
+        }
+        let exited = waitable.find(|id| {
+            processes
+                .get(id)
+                .is_some_and(|child| *child.state.lock() == ProcessState::Terminated)
+        });
+        let Some(child_id) = exited else {
+            return WaitResult::Pending(parent.child_exited.clone());
+        };
+        children.retain(|&id| id != child_id);
+        drop(children);
+        let process = processes
+            .remove(&child_id)
+            .expect("terminated child disappeared");
+        let status = *process.exit_status.lock();
+        WaitResult::Exited {
+            id: child_id,
+            status,
+            process,
+        }
+    }
+
+    // 実行中のプロセスをwake_atまでBlockedにする
+    pub fn sleep_current(&self, wake_at: Instant) {
+        let processes = self.processes.lock();
+        if let Some(process) = self.current.lock().and_then(|id| processes.get(&id)) {
+            *process.wake_at.lock() = Some(wake_at);
+            *process.state.lock() = ProcessState::Blocked;
+        }
+        self.request_reschedule();
+    }
+
+    // 実行中のプロセスをBlockedにしてIDを返す。wakeされるまでスケジュールされない
+    pub fn block_current(&self) -> Option<ProcessId> {
+        let processes = self.processes.lock();
+        let process = self.current.lock().and_then(|id| processes.get(&id))?;
+        *process.state.lock() = ProcessState::Blocked;
+        Some(process.id)
+    }
+
+    // BlockedのプロセスをReadyに戻してキューに入れる
+    pub fn wake(&self, id: ProcessId) {
+        let processes = self.processes.lock();
+        let Some(process) = processes.get(&id) else {
+            return;
+        };
+        let mut state = process.state.lock();
+        if *state == ProcessState::Blocked {
+            *state = ProcessState::Ready;
+            drop(state);
+            self.enqueue(process);
+        }
+    }
+
+    pub fn is_blocked(&self, id: ProcessId) -> bool {
+        self.processes
+            .lock()
+            .get(&id)
+            .is_some_and(|process| *process.state.lock() == ProcessState::Blocked)
+    }
+
+    // 眠っているプロセスのうち、起きる時刻を過ぎたものをReadyQueueに戻す
+    pub fn wake_sleepers(&self, now: Instant) {
+        let processes = self.processes.lock();
+        for process in processes.values() {
+            let mut wake_at = process.wake_at.lock();
+            if wake_at.is_some_and(|wake_at| wake_at <= now) {
+                *wake_at = None;
+                *process.state.lock() = ProcessState::Ready;
+                self.enqueue(process);
+            }
+        }
+    }
+
+    pub fn request_reschedule(&self) {
+        self.need_resched.store(true, Ordering::Relaxed);
+    }
+
+    pub fn take_reschedule(&self) -> bool {
+        self.need_resched.swap(false, Ordering::Relaxed)
+    }
+
+    // 終了したプロセスを表から取り除いて返す
+    // 親がwaitで終了ステータスを受け取るまでは残すが、親がいないか親も終了していれば
+    // もう受け取られることはない
+    // 自分自身のスタック上では解放できないので、実行中のプロセスは残す
+    // 表に入っていたBoxをそのまま返すので、Vec<Box<_>>になる
+    #[allow(clippy::vec_box)]
+    fn reap_terminated(&self) -> Vec<Box<Process>> {
+        let current = *self.current.lock();
+        let mut processes = self.processes.lock();
+        let is_terminated = |process: &Process| *process.state.lock() == ProcessState::Terminated;
+        let reapable: Vec<ProcessId> = processes
+            .values()
+            .filter(|process| {
+                Some(process.id) != current
+                    && is_terminated(process)
+                    && process
+                        .parent_id
+                        .and_then(|id| processes.get(&id))
+                        .is_none_or(|parent| is_terminated(parent))
+            })
+            .map(|process| process.id)
+            .collect();
+        reapable
+            .into_iter()
+            .filter_map(|id| processes.remove(&id))
+            .collect()
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(policy::boot_policy())
+    }
+}
+
+/// 終了したプロセスのスタックやアドレス空間を解放する
+/// 解放するときにフレームアロケータやカーネルスタックのロックを取るので、
+/// SCHEDULERのロックを手放して割り込みを許可した状態でdropする
+pub fn reap() {
+    let reaped = SCHEDULER
+        .get()
+        .map(|scheduler| scheduler.lock().reap_terminated());
+    drop(reaped);
+}
+
+// reaperが終了したプロセスを探す間隔
+const REAP_INTERVAL_MS: u64 = 1000;
+
+/// entryから実行するカーネルスレッドを作る
+/// entryがreturnするとprocess_exitに戻って終了する
+pub fn spawn_kernel_thread(entry: extern "C" fn()) -> Result<ProcessId, VmError> {
+    let scheduler = SCHEDULER.get().expect("scheduler is not initialized");
+    scheduler.lock().create_process(entry as usize as u64, None)
+}
+
+// 親に回収されないまま終了したプロセスを定期的に解放するカーネルスレッド
+// forkやexecのときにも回収するが、それまでスタックやアドレス空間が残らないようにする
+pub extern "C" fn reaper() {
+    loop {
+        reap();
+        sleep_ms(REAP_INTERVAL_MS);
+    }
+}
+
+/// 実行中のカーネルスレッドをmsミリ秒眠らせる
+pub fn sleep_ms(ms: u64) {
+    use x86_64::instructions::hlt;
+
+    let Some(scheduler) = SCHEDULER.get() else {
+        return;
+    };
+    let current = {
+        let scheduler = scheduler.lock();
+        scheduler.sleep_current(Instant::now() + Duration::from_millis(ms));
+        scheduler.current()
+    };
+    // 起こされるまでは、タイマ割り込みでほかのプロセスに切り替わるのを待つ
+    while current.is_some_and(|id| scheduler.lock().is_blocked(id)) {
+        hlt();
+    }
+}
+
+// プロセスのエントリ関数がreturnしたときの戻り先
+pub extern "C" fn process_exit() -> ! {
+    exit(0)
+}
+
+/// 実行中のプロセスをTerminatedにして、waitで待っている親を起こす
+/// 実際に切り替わるのは次のスケジュールのとき
+/// Terminatedにしてから親を起こす前に切り替わると二度と戻ってこないので、その間は割り込みを禁止する
+pub fn terminate(status: i32) {
+    use x86_64::instructions::interrupts;
+
+    let Some(scheduler) = SCHEDULER.get() else {
+        return;
+    };
+    interrupts::without_interrupts(|| {
+        let child_exited = scheduler.lock().exit_current(status);
+        if let Some(child_exited) = child_exited {
+            child_exited.release();
+        }
+    });
+}
+
+/// 実行中のプロセスをstatusで終了させる
+/// 今のスタックの上で、別のプロセスに切り替わるまで待つ
+/// 割り込みはTerminatedにしてから許可するので、例外ハンドラから割り込みを禁止したまま呼んでもよい
+/// その後に切り替わっても、このプロセスに戻ってくることはない
+pub fn exit(status: i32) -> ! {
+    use x86_64::instructions::{hlt, interrupts};
+
+    terminate(status);
+    interrupts::enable();
+    loop {
+        hlt();
     }
 }
@@ -0,0 +1,79 @@
+//! 仮想実行時間(vruntime)の小さいものから実行する公平スケジューリング
+//!
+//! vruntimeは実行した時間をniceの重みで割ったもので、重いプロセスほどゆっくり増える
+//! 待ちプロセスはvruntimeの順に並べておく。BTreeSetは挿入でノードを確保するので
+//! 割り込みコンテキストでは使えず、容量を予約しておける二分ヒープで木を作る
+
+use alloc::collections::binary_heap::BinaryHeap;
+use core::cmp::Reverse;
+
+use crate::process::ProcessId;
+
+use super::policy::{SchedEntity, SchedulingPolicy};
+
+// nice 0の重み。1ティックでvruntimeがNICE_0_WEIGHT * TICK_VRUNTIME / 重みだけ増える
+const NICE_0_WEIGHT: u64 = 1024;
+const TICK_VRUNTIME: u64 = 1000;
+// 一度選ばれたら最低でもこれだけは続けて実行する
+const MIN_GRANULARITY: u64 = 2;
+// 待ちプロセスとのvruntimeの差がこれを超えたら譲る
+const WAKEUP_GRANULARITY: u64 = TICK_VRUNTIME;
+
+// niceごとの重み。nice 1つでCPU時間がおよそ1.25倍違うようにする(Linuxと同じ値)
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+pub struct FairPolicy {
+    ready_queue: BinaryHeap<Reverse<(u64, ProcessId)>>,
+    // 待ちプロセスと実行中のプロセスのvruntimeの下限
+    // 新しく入ってきたプロセスがこれより小さい値でCPUを独占しないようにする
+    min_vruntime: u64,
+}
+
+impl FairPolicy {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BinaryHeap::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl SchedulingPolicy for FairPolicy {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn reserve(&mut self, processes: usize) {
+        self.ready_queue
+            .reserve(processes.saturating_sub(self.ready_queue.len()));
+    }
+
+    fn enqueue(&mut self, id: ProcessId, entity: &mut SchedEntity) {
+        entity.vruntime = entity.vruntime.max(self.min_vruntime);
+        self.ready_queue.push(Reverse((entity.vruntime, id)));
+    }
+
+    fn pick_next(&mut self) -> Option<ProcessId> {
+        let Reverse((vruntime, id)) = self.ready_queue.pop()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(id)
+    }
+
+    fn charge(&mut self, entity: &mut SchedEntity) {
+        entity.vruntime += NICE_0_WEIGHT * TICK_VRUNTIME / WEIGHTS[entity.level()];
+    }
+
+    fn should_preempt(&self, current: &SchedEntity, ran: u64) -> bool {
+        ran >= MIN_GRANULARITY
+            && self
+                .ready_queue
+                .peek()
+                .is_some_and(|Reverse((vruntime, _))| {
+                    vruntime + WAKEUP_GRANULARITY < current.vruntime
+                })
+    }
+}
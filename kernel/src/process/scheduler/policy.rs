@@ -0,0 +1,64 @@
+//! スケジューリングポリシー
+//!
+//! Schedulerはプロセスの状態と切り替えを受け持ち、実行可能なプロセスのうち
+//! どれを次に動かすかだけをポリシーに任せる
+//! ポリシーはビルド時のフィーチャで選び、起動したときのSchedulerに組み込む
+
+use alloc::boxed::Box;
+
+use crate::process::ProcessId;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// プロセスごとのスケジューリングの情報
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SchedEntity {
+    // 小さいほど優先される。NICE_MIN..=NICE_MAX
+    pub nice: i8,
+    // 公平スケジューラの仮想実行時間
+    pub vruntime: u64,
+    // 実行したティック数
+    pub cpu_ticks: u64,
+}
+
+impl SchedEntity {
+    // niceを0から始まる優先度の段階にする。0が一番高い
+    pub fn level(&self) -> usize {
+        (self.nice - NICE_MIN) as usize
+    }
+}
+
+/// 実行可能なプロセスの順番を決める
+/// enqueue、pick_next、charge、should_preemptは割り込みコンテキストから呼ばれるので
+/// ヒープを確保してはいけない。必要な容量はreserveで先に確保しておく
+pub trait SchedulingPolicy: Send {
+    fn name(&self) -> &'static str;
+
+    /// プロセスがprocesses個になるまで、確保せずにenqueueできるようにする
+    fn reserve(&mut self, processes: usize);
+
+    /// 実行可能になったプロセスをキューに入れる
+    fn enqueue(&mut self, id: ProcessId, entity: &mut SchedEntity);
+
+    /// 次に実行するプロセスをキューから取り出す
+    /// 取り出したプロセスがもう実行可能でないこともあるので、呼び出し側で確かめる
+    fn pick_next(&mut self) -> Option<ProcessId>;
+
+    /// 実行中のプロセスが1ティック分CPUを使った
+    fn charge(&mut self, _entity: &mut SchedEntity) {}
+
+    /// 実行中のプロセスを切り替えるべきか。ranは切り替わってから続けて実行したティック数
+    fn should_preempt(&self, current: &SchedEntity, ran: u64) -> bool;
+}
+
+/// フィーチャで選んだポリシー
+pub fn boot_policy() -> Box<dyn SchedulingPolicy> {
+    if cfg!(feature = "sched-fair") {
+        Box::new(super::fair::FairPolicy::new())
+    } else if cfg!(feature = "sched-priority") {
+        Box::new(super::priority::PriorityPolicy::new())
+    } else {
+        Box::new(super::round_robin::RoundRobinPolicy::new())
+    }
+}
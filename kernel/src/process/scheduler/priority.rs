@@ -0,0 +1,79 @@
+//! niceで決まる優先度の高いものから実行する固定優先度スケジューリング
+//!
+//! 低い優先度のプロセスが飢えないように、待っている間にほかのプロセスが
+//! AGING_PICKS回選ばれるたびに1段階ずつ優先度を上げて扱う(エージング)
+
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::process::ProcessId;
+
+use super::policy::{SchedEntity, SchedulingPolicy, NICE_MAX, NICE_MIN};
+
+const LEVELS: usize = (NICE_MAX - NICE_MIN) as usize + 1;
+const TIME_SLICE: u64 = 10;
+const AGING_PICKS: u64 = 4;
+
+pub struct PriorityPolicy {
+    // 優先度の段階ごとの、プロセスとキューに入れたときのpicks
+    queues: [VecDeque<(ProcessId, u64)>; LEVELS],
+    // これまでにpick_nextで選んだ回数
+    picks: u64,
+}
+
+impl PriorityPolicy {
+    pub fn new() -> Self {
+        Self {
+            queues: core::array::from_fn(|_| VecDeque::new()),
+            picks: 0,
+        }
+    }
+
+    // 待っていた分だけ上げた優先度
+    fn effective_level(&self, level: usize, enqueued_at: u64) -> usize {
+        level.saturating_sub(((self.picks - enqueued_at) / AGING_PICKS) as usize)
+    }
+
+    // 一番優先度の高い待ちプロセスの段階と、それがいるキュー
+    // 同じ段階のキューでは先頭が一番長く待っているので、先頭だけを比べればよい
+    fn best(&self) -> Option<(usize, usize)> {
+        self.queues
+            .iter()
+            .enumerate()
+            .filter_map(|(level, queue)| {
+                let &(_, enqueued_at) = queue.front()?;
+                Some((self.effective_level(level, enqueued_at), level))
+            })
+            .min()
+    }
+}
+
+impl SchedulingPolicy for PriorityPolicy {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn reserve(&mut self, processes: usize) {
+        // どの段階に入るかは分からないので、どの段階にも全プロセスが入れるようにする
+        for queue in &mut self.queues {
+            queue.reserve(processes.saturating_sub(queue.len()));
+        }
+    }
+
+    fn enqueue(&mut self, id: ProcessId, entity: &mut SchedEntity) {
+        self.queues[entity.level()].push_back((id, self.picks));
+    }
+
+    fn pick_next(&mut self) -> Option<ProcessId> {
+        let (_, level) = self.best()?;
+        self.picks += 1;
+        self.queues[level].pop_front().map(|(id, _)| id)
+    }
+
+    fn should_preempt(&self, current: &SchedEntity, ran: u64) -> bool {
+        // 優先度の高いプロセスが待っていれば、タイムスライスを使い切る前でも譲る
+        ran >= TIME_SLICE
+            || self
+                .best()
+                .is_some_and(|(level, _)| level < current.level())
+    }
+}
@@ -0,0 +1,45 @@
+//! 到着順に一定のティックずつ実行するラウンドロビン
+
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::process::ProcessId;
+
+use super::policy::{SchedEntity, SchedulingPolicy};
+
+// 1回に続けて実行するティック数
+const TIME_SLICE: u64 = 10;
+
+pub struct RoundRobinPolicy {
+    ready_queue: VecDeque<ProcessId>,
+}
+
+impl RoundRobinPolicy {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl SchedulingPolicy for RoundRobinPolicy {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn reserve(&mut self, processes: usize) {
+        self.ready_queue
+            .reserve(processes.saturating_sub(self.ready_queue.len()));
+    }
+
+    fn enqueue(&mut self, id: ProcessId, _entity: &mut SchedEntity) {
+        self.ready_queue.push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ProcessId> {
+        self.ready_queue.pop_front()
+    }
+
+    fn should_preempt(&self, _current: &SchedEntity, ran: u64) -> bool {
+        ran >= TIME_SLICE
+    }
+}
@@ -0,0 +1,41 @@
+use x86_64::VirtAddr;
+
+use crate::memory::vmm::{self, VmError};
+
+/// プロセスごとに確保するカーネルスタック
+/// 下にガードページを置いたカーネルスタック用の領域に確保するので、溢れるとページフォルトになる
+pub struct KernelStack {
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    pub const SIZE: usize = 4096 * 4;
+
+    pub fn new() -> Self {
+        Self::try_new().expect("failed to allocate a kernel stack")
+    }
+
+    /// 確保できなかったときはエラーを返す
+    pub fn try_new() -> Result<Self, VmError> {
+        Ok(Self {
+            top: vmm::alloc_stack(Self::SIZE as u64)?,
+        })
+    }
+
+    /// スタックは上位アドレスから下位アドレスに向かって伸びる
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// addrがこのスタックのすぐ下のガードページの中か
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        let bottom = self.top - Self::SIZE as u64;
+        addr < bottom && bottom - addr <= 4096
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vmm::free_stack(self.top, Self::SIZE as u64);
+    }
+}
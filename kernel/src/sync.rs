@@ -0,0 +1,15 @@
+//! 待っている間プロセスを眠らせる同期プリミティブ
+//!
+//! spin::MutexやSpinlockは空くまで回り続けるが、ここの型は待っている間プロセスを
+//! Blockedにしてほかのプロセスに譲り、空いたときにReadyに戻す
+//! 眠るには割り込みが許可されている必要があるので、割り込みハンドラの中では使わない
+//! 割り込みハンドラと共有するデータにはirq_spinlockを使う
+
+pub mod condvar;
+pub mod irq_spinlock;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod wait_queue;
@@ -0,0 +1,52 @@
+use super::{mutex::MutexGuard, wait_queue::WaitQueue};
+
+/// Mutexで守られた条件が変わるのを眠って待つ条件変数
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// guardのロックを外して通知を待ち、起きたらロックを取り直して返す
+    /// 通知が無くても戻ることがあるので、条件はループで調べる
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // キューのロックを持ったままMutexを外すので、外した直後の通知も取りこぼさない
+        self.waiters.wait_if(move || {
+            drop(guard);
+            true
+        });
+        mutex.lock()
+    }
+
+    /// conditionがtrueを返す間待つ
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,138 @@
+#[cfg(feature = "lockdep")]
+use core::{any::type_name, panic::Location};
+use core::{
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::rflags::{self, RFlags},
+};
+
+/// 持っている間は割り込みを禁止するSpinlock
+/// 割り込みハンドラからも取るロックに使う。取る前のRFLAGSを覚えておき、手放すときに元に戻す
+pub struct IrqSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    // newを呼んだ場所。lockdepはこれでロックのクラスを分ける
+    #[cfg(feature = "lockdep")]
+    site: &'static Location<'static>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+
+impl<T> IrqSpinlock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(feature = "lockdep")]
+            site: Location::caller(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let rflags = save_and_disable();
+        // 待ち始める前に調べて、デッドロックするなら止まる前に報告する
+        #[cfg(feature = "lockdep")]
+        super::lockdep::acquire(self.site, type_name::<T>(), false);
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        IrqSpinlockGuard { lock: self, rflags }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let rflags = save_and_disable();
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                #[cfg(feature = "lockdep")]
+                super::lockdep::acquire(self.site, type_name::<T>(), true);
+                Some(IrqSpinlockGuard { lock: self, rflags })
+            }
+            Err(_) => {
+                restore(rflags);
+                None
+            }
+        }
+    }
+
+    /// ロックされているか
+    /// 持っている間は割り込まれないので、1つのCPUで割り込みを禁止して調べたときに
+    /// ロックされていれば、持っているのは自分自身
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    rflags: RFlags,
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // ロックを手放してから割り込みを戻す。逆だと手放す前に割り込みが入って同じロックを取りにくる
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.lock.site);
+        self.lock.locked.store(false, Ordering::Release);
+        restore(self.rflags);
+    }
+}
+
+fn save_and_disable() -> RFlags {
+    let rflags = rflags::read();
+    interrupts::disable();
+    rflags
+}
+
+fn restore(rflags: RFlags) {
+    if rflags.contains(RFlags::INTERRUPT_FLAG) {
+        interrupts::enable();
+    }
+}
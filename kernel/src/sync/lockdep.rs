@@ -0,0 +1,265 @@
+//! IrqSpinlockを取る順番の検査(lockdepフィーチャ)
+//!
+//! ロックはnewを呼んだ場所ごとにクラスに分け、あるクラスを持ったまま別のクラスを取った順番を記録する
+//! 記録と逆の順番で取ろうとしたときや、持っているクラスをもう一度取ろうとしたときは、
+//! 前に取った場所のバックトレースを表示してpanicし、今の場所のバックトレースはpanicハンドラが表示する
+//! アロケータのロックからも呼ばれるので、ヒープは使わずに決まった大きさの表に入れる
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{backtrace, println};
+
+const MAX_CLASSES: usize = 16;
+const MAX_HELD: usize = 8;
+const TRACE_DEPTH: usize = 8;
+
+static LOCKDEP: Mutex<LockDep> = Mutex::new(LockDep::new());
+// 一度報告したら止める。報告を表示するのにもCONSOLEのロックを取るため
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[derive(Clone, Copy)]
+struct Trace {
+    frames: [u64; TRACE_DEPTH],
+    len: usize,
+}
+
+impl Trace {
+    const EMPTY: Self = Self {
+        frames: [0; TRACE_DEPTH],
+        len: 0,
+    };
+
+    fn capture() -> Self {
+        let mut frames = [0; TRACE_DEPTH];
+        let len = backtrace::capture(&mut frames);
+        Self { frames, len }
+    }
+
+    fn print(&self) {
+        backtrace::print_frames(self.frames[..self.len].iter().copied());
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Class {
+    site: &'static Location<'static>,
+    name: &'static str,
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.site)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    trace: Trace,
+}
+
+enum Violation {
+    // 持っているクラスをもう一度取ろうとした
+    Recursive {
+        class: Class,
+        held: Trace,
+    },
+    // 前にclassを持ったままviaを取っていて、viaからはheld_classに辿り着く
+    Inversion {
+        class: Class,
+        held_class: Class,
+        held: Trace,
+        via: Class,
+        earlier: Trace,
+    },
+}
+
+struct LockDep {
+    classes: [Option<Class>; MAX_CLASSES],
+    // after[a][b]: aを持ったままbを取ったことがあれば、初めてそうしたときのバックトレース
+    after: [[Option<Trace>; MAX_CLASSES]; MAX_CLASSES],
+    // このCPUが持っているロックを取った順に並べたもの
+    // CPUは1つしか動かしていないので1組だけ持つ
+    held: [Held; MAX_HELD],
+    depth: usize,
+}
+
+impl LockDep {
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            after: [[None; MAX_CLASSES]; MAX_CLASSES],
+            held: [Held {
+                class: 0,
+                trace: Trace::EMPTY,
+            }; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    fn find(&self, site: &'static Location<'static>) -> Option<usize> {
+        self.classes
+            .iter()
+            .position(|class| matches!(class, Some(class) if class.site == site))
+    }
+
+    // 表がいっぱいのときは、そのクラスは調べない
+    fn register(&mut self, site: &'static Location<'static>, name: &'static str) -> Option<usize> {
+        if let Some(class) = self.find(site) {
+            return Some(class);
+        }
+        let class = self.classes.iter().position(Option::is_none)?;
+        self.classes[class] = Some(Class { site, name });
+        Some(class)
+    }
+
+    fn class(&self, class: usize) -> Class {
+        self.classes[class].unwrap()
+    }
+
+    /// fromを持ったまま取った順番を辿ってtoに着くなら、fromから最初に取ったクラス
+    fn path(&self, from: usize, to: usize) -> Option<usize> {
+        // 幅優先で辿り、それぞれのクラスにfromからどのクラスを通って来たかを覚えておく
+        let mut first = [None; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let mut tail = 0;
+        for (next, order) in self.after[from].iter().enumerate() {
+            if order.is_some() {
+                first[next] = Some(next);
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+        let mut head = 0;
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == to {
+                return first[class];
+            }
+            for next in 0..MAX_CLASSES {
+                if self.after[class][next].is_some() && first[next].is_none() {
+                    first[next] = first[class];
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn acquire(
+        &mut self,
+        site: &'static Location<'static>,
+        name: &'static str,
+        trylock: bool,
+    ) -> Option<Violation> {
+        let class = self.register(site, name)?;
+        let trace = Trace::capture();
+        // try_lockは取れなくても待たないので、デッドロックにはならない
+        if !trylock {
+            for held in &self.held[..self.depth] {
+                if held.class == class {
+                    return Some(Violation::Recursive {
+                        class: self.class(class),
+                        held: held.trace,
+                    });
+                }
+                if let Some(via) = self.path(class, held.class) {
+                    return Some(Violation::Inversion {
+                        class: self.class(class),
+                        held_class: self.class(held.class),
+                        held: held.trace,
+                        via: self.class(via),
+                        earlier: self.after[class][via].unwrap(),
+                    });
+                }
+            }
+            for index in 0..self.depth {
+                let held = self.held[index].class;
+                self.after[held][class].get_or_insert(trace);
+            }
+        }
+        if self.depth < MAX_HELD {
+            self.held[self.depth] = Held { class, trace };
+            self.depth += 1;
+        }
+        None
+    }
+
+    fn release(&mut self, site: &'static Location<'static>) {
+        let Some(class) = self.find(site) else {
+            return;
+        };
+        // 取った順に手放すとは限らないので、最後に取ったものを探して詰める
+        if let Some(index) = self.held[..self.depth]
+            .iter()
+            .rposition(|held| held.class == class)
+        {
+            self.held.copy_within(index + 1..self.depth, index);
+            self.depth -= 1;
+        }
+    }
+}
+
+/// siteで作ったロックを取る前に呼ぶ
+/// try_lockのときは取れた後に呼び、順番は調べずに持っているものとして記録だけする
+pub fn acquire(site: &'static Location<'static>, name: &'static str, trylock: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let violation = LOCKDEP.lock().acquire(site, name, trylock);
+    if let Some(violation) = violation {
+        report(violation);
+    }
+}
+
+/// siteで作ったロックを手放すときに呼ぶ
+pub fn release(site: &'static Location<'static>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    LOCKDEP.lock().release(site);
+}
+
+// CONSOLEを持っているときに見つけた場合は、表示できずにそのまま止まる
+fn report(violation: Violation) -> ! {
+    ENABLED.store(false, Ordering::Relaxed);
+    match violation {
+        Violation::Recursive { class, held } => {
+            println!("lockdep: recursive locking of {}", class);
+            println!("already held from:");
+            held.print();
+            panic!("lockdep: recursive locking of {}", class.name)
+        }
+        Violation::Inversion {
+            class,
+            held_class,
+            held,
+            via,
+            earlier,
+        } => {
+            println!(
+                "lockdep: lock order inversion: acquiring {} while holding {}",
+                class, held_class
+            );
+            println!("{} was held from:", held_class);
+            held.print();
+            println!("but earlier {} was held while acquiring {} at:", class, via);
+            earlier.print();
+            if via.site != held_class.site {
+                println!("and {} is taken before {}", via, held_class);
+            }
+            panic!(
+                "lockdep: lock order inversion between {} and {}",
+                class.name, held_class.name
+            )
+        }
+    }
+}
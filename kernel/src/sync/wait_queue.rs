@@ -0,0 +1,93 @@
+use alloc::collections::vec_deque::VecDeque;
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::{
+    process::{scheduler::SCHEDULER, ProcessId},
+    sync::irq_spinlock::IrqSpinlock,
+};
+
+/// 条件が満たされるのを眠って待つプロセスの列
+pub struct WaitQueue {
+    // 割り込みを禁止した状態で起こすこともあるので、持っている間に切り替わらないようにする
+    waiters: IrqSpinlock<VecDeque<ProcessId>>,
+}
+
+impl WaitQueue {
+    // lockdepはロックを作った場所で区別するので、呼び出し元(MutexやCondvarのnew)の場所で区別させる
+    // CondvarはキューのロックのままMutexを外すので、ここの場所だと同じロックの入れ子に見える
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinlock::new(VecDeque::new()),
+        }
+    }
+
+    /// should_waitがtrueなら、起こされるまで実行中のプロセスを眠らせる
+    /// 条件はキューのロックを持ったまま調べるので、条件を変えてから起こす側と行き違わない
+    /// 起こされても条件が満たされているとは限らないので、呼び出し側で調べ直す
+    pub fn wait_if(&self, should_wait: impl FnOnce() -> bool) {
+        let mut waiters = self.waiters.lock();
+        if !should_wait() {
+            return;
+        }
+        let Some(id) = block_current() else {
+            // スケジューラが動き出す前は眠れないので、呼び出し側で回って待つ
+            drop(waiters);
+            core::hint::spin_loop();
+            return;
+        };
+        waiters.push_back(id);
+        drop(waiters);
+        sleep(id);
+    }
+
+    /// 一番長く待っているプロセスを1つ起こす
+    pub fn wake_one(&self) -> bool {
+        let id = self.waiters.lock().pop_front();
+        if let Some(id) = id {
+            wake(id);
+        }
+        id.is_some()
+    }
+
+    /// 待っているプロセスを全部起こし、その数を返す
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for &id in &waiters {
+            wake(id);
+        }
+        waiters.len()
+    }
+}
+
+impl Default for WaitQueue {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn block_current() -> Option<ProcessId> {
+    SCHEDULER.get()?.lock().block_current()
+}
+
+fn wake(id: ProcessId) {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.lock().wake(id);
+    }
+}
+
+/// wakeされるまで待つ
+/// BlockedのプロセスはReadyQueueに戻されないので、次のタイマ割り込みでほかのプロセスに切り替わる
+fn sleep(id: ProcessId) {
+    debug_assert!(
+        interrupts::are_enabled(),
+        "sleeping with interrupts disabled"
+    );
+    while SCHEDULER
+        .get()
+        .is_some_and(|scheduler| scheduler.lock().is_blocked(id))
+    {
+        hlt();
+    }
+}
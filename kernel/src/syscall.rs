@@ -0,0 +1,538 @@
+//! SYSCALL/SYSRETによるシステムコール
+//!
+//! 呼び出し規約はLinuxに合わせる
+//! rax: システムコール番号
+//! rdi, rsi, rdx, r10, r8, r9: 引数
+//! rax: 戻り値(エラーのときは負のエラー番号)
+
+use alloc::sync::Arc;
+use core::arch::global_asm;
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    fs::{
+        file::{OpenFile, OpenFlags, SeekFrom},
+        vfs::{self, FileType},
+        FsError,
+    },
+    gdt,
+    interrupts::{restore_registers, save_registers},
+    memory::vmm::Access,
+    process::{
+        scheduler::{self, WaitResult, SCHEDULER},
+        TrapFrame,
+    },
+    time::{Duration, Instant},
+};
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_GETPID: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_READ: u64 = 5;
+pub const SYS_OPEN: u64 = 6;
+pub const SYS_CLOSE: u64 = 7;
+pub const SYS_SEEK: u64 = 8;
+pub const SYS_STAT: u64 = 9;
+pub const SYS_READDIR: u64 = 10;
+pub const SYS_MKDIR: u64 = 11;
+pub const SYS_UNLINK: u64 = 12;
+pub const SYS_RENAME: u64 = 13;
+pub const SYS_TRUNCATE: u64 = 14;
+pub const SYS_FORK: u64 = 15;
+pub const SYS_WAIT: u64 = 16;
+pub const SYS_NICE: u64 = 17;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+// パスの長さの上限
+const PATH_MAX: u64 = 4096;
+
+/// statで返すファイルの情報
+#[repr(C)]
+struct Stat {
+    // 1: ファイル, 2: ディレクトリ, 3: キャラクタデバイス
+    file_type: u64,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    NoEntry = 2,
+    BadFileDescriptor = 9,
+    NoChild = 10,
+    TryAgain = 11,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    Busy = 16,
+    AlreadyExists = 17,
+    CrossDevice = 18,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyOpenFiles = 24,
+    NoSpace = 28,
+    ReadOnlyFileSystem = 30,
+    NoSuchSyscall = 38,
+    DirectoryNotEmpty = 39,
+}
+
+impl From<FsError> for SyscallError {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotFound => SyscallError::NoEntry,
+            FsError::AlreadyExists => SyscallError::AlreadyExists,
+            FsError::NotADirectory => SyscallError::NotADirectory,
+            FsError::IsADirectory => SyscallError::IsADirectory,
+            FsError::ReadOnly => SyscallError::ReadOnlyFileSystem,
+            FsError::InvalidArgument => SyscallError::InvalidArgument,
+            FsError::BadFileDescriptor => SyscallError::BadFileDescriptor,
+            FsError::TooManyOpenFiles => SyscallError::TooManyOpenFiles,
+            FsError::WouldBlock => SyscallError::TryAgain,
+            FsError::NoSpace => SyscallError::NoSpace,
+            FsError::DirectoryNotEmpty => SyscallError::DirectoryNotEmpty,
+            FsError::CrossDevice => SyscallError::CrossDevice,
+            FsError::Busy => SyscallError::Busy,
+        }
+    }
+}
+
+impl SyscallError {
+    fn to_return_value(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+type SyscallHandler = fn(&[u64; 6]) -> SyscallResult;
+
+// システムコール番号からハンドラを引く
+fn syscall_handler(number: u64) -> Option<SyscallHandler> {
+    let handler: SyscallHandler = match number {
+        SYS_WRITE => sys_write,
+        SYS_EXIT => sys_exit,
+        SYS_YIELD => sys_yield,
+        SYS_GETPID => sys_getpid,
+        SYS_SLEEP => sys_sleep,
+        SYS_READ => sys_read,
+        SYS_OPEN => sys_open,
+        SYS_CLOSE => sys_close,
+        SYS_SEEK => sys_seek,
+        SYS_STAT => sys_stat,
+        SYS_READDIR => sys_readdir,
+        SYS_MKDIR => sys_mkdir,
+        SYS_UNLINK => sys_unlink,
+        SYS_RENAME => sys_rename,
+        SYS_TRUNCATE => sys_truncate,
+        SYS_FORK => sys_fork,
+        SYS_WAIT => sys_wait,
+        SYS_NICE => sys_nice,
+        _ => return None,
+    };
+    Some(handler)
+}
+
+// syscall命令はスタックを切り替えないので、エントリで使うカーネルスタックと
+// 退避したユーザのrspをここに置く
+static mut KERNEL_STACK_TOP: u64 = 0;
+static mut USER_RSP: u64 = 0;
+// TrapFrameを割り込みと同じ形にするために積むセレクタ
+static mut USER_CS: u64 = 0;
+static mut USER_SS: u64 = 0;
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// 割り込みと同じ並びのTrapFrameを組み立ててからディスパッチする
+// プロセスが切り替わらなかったときはsysretqで、切り替わったときはiretqで戻る
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {kernel_stack_top}]",
+    "push qword ptr [rip + {user_ss}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push r11",
+    "push qword ptr [rip + {user_cs}]",
+    "push rcx",
+    save_registers!(),
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "test al, al",
+    restore_registers!(),
+    "jz 2f",
+    "pop rcx",
+    "add rsp, 8",
+    "pop r11",
+    "pop rsp",
+    "sysretq",
+    "2:",
+    "iretq",
+    user_rsp = sym USER_RSP,
+    kernel_stack_top = sym KERNEL_STACK_TOP,
+    user_cs = sym USER_CS,
+    user_ss = sym USER_SS,
+    dispatch = sym syscall_dispatch,
+);
+
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        USER_CS = selectors.user_code_selector.0 as u64;
+        USER_SS = selectors.user_data_selector.0 as u64;
+    }
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("invalid segment selectors for SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // カーネルに入ったら割り込みを禁止し、ディスパッチ中に必要なときだけ許可する
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// syscall命令で入ったときに使うカーネルスタックを設定する
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        KERNEL_STACK_TOP = stack_top.as_u64();
+    }
+}
+
+// sysretqで戻れるときにtrueを返す
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) -> bool {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+
+    // ハンドラの実行中はタイマ割り込みでプロセスを切り替えられるようにする
+    interrupts::enable();
+    let result = match syscall_handler(frame.rax) {
+        Some(handler) => handler(&args),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    interrupts::disable();
+
+    // 切り替える前に戻り値を書いておく
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.to_return_value(),
+    };
+
+    let Some(scheduler) = SCHEDULER.get() else {
+        return true;
+    };
+    let scheduler = scheduler.lock();
+    if scheduler.take_reschedule() {
+        !scheduler.context_switch(frame)
+    } else {
+        true
+    }
+}
+
+// ユーザが渡したバッファを検証してスライスにする
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    check_user_buffer(ptr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+fn user_slice_mut(ptr: u64, len: u64) -> Result<&'static mut [u8], SyscallError> {
+    check_user_buffer(ptr, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+fn check_user_buffer(ptr: u64, len: u64, writable: bool) -> Result<(), SyscallError> {
+    let addr = VirtAddr::try_new(ptr).map_err(|_| SyscallError::BadAddress)?;
+    let address_space = SCHEDULER
+        .get()
+        .and_then(|scheduler| scheduler.lock().current_address_space())
+        .ok_or(SyscallError::BadAddress)?;
+    let mut address_space = address_space.lock();
+    let access = if writable {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    if !address_space.check_access(addr, len, access) {
+        return Err(SyscallError::BadAddress);
+    }
+    // カーネルの中でページフォルトを起こさないように、先にフレームの割り当てと
+    // コピーオンライトのページのコピーを済ませておく
+    address_space
+        .populate(addr, len, access)
+        .map_err(|_| SyscallError::BadAddress)
+}
+
+fn user_str(ptr: u64, len: u64) -> Result<&'static str, SyscallError> {
+    if len > PATH_MAX {
+        return Err(SyscallError::InvalidArgument);
+    }
+    core::str::from_utf8(user_slice(ptr, len)?).map_err(|_| SyscallError::InvalidArgument)
+}
+
+// 実行中のプロセスのfdに対応するファイル
+// 読み書きの間スケジューラのロックを持ち続けないように、参照を複製して返す
+fn current_file(fd: u64) -> Result<Arc<OpenFile>, SyscallError> {
+    SCHEDULER
+        .get()
+        .and_then(|scheduler| {
+            scheduler
+                .lock()
+                .with_current_files(|files| files.get(fd as usize))
+        })
+        .ok_or(SyscallError::BadFileDescriptor)?
+        .map_err(SyscallError::from)
+}
+
+// write(fd, buf, len)
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = current_file(fd)?;
+    let bytes = user_slice(buf, len)?;
+    Ok(file.write(bytes)? as u64)
+}
+
+// read(fd, buf, len)
+fn sys_read(args: &[u64; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = current_file(fd)?;
+    let bytes = user_slice_mut(buf, len)?;
+    Ok(file.read(bytes)? as u64)
+}
+
+// open(path, path_len, flags)
+fn sys_open(args: &[u64; 6]) -> SyscallResult {
+    let [path, path_len, flags, ..] = *args;
+    let path = user_str(path, path_len)?;
+    let file = OpenFile::open(path, OpenFlags::from_bits(flags)?)?;
+    let scheduler = SCHEDULER.get().ok_or(SyscallError::InvalidArgument)?;
+    let fd = scheduler
+        .lock()
+        .with_current_files(|files| files.insert(file))
+        .ok_or(SyscallError::InvalidArgument)??;
+    Ok(fd as u64)
+}
+
+// close(fd)
+fn sys_close(args: &[u64; 6]) -> SyscallResult {
+    let fd = args[0];
+    let scheduler = SCHEDULER.get().ok_or(SyscallError::BadFileDescriptor)?;
+    // 最後の参照を落とすのはロックを外してから
+    let file = scheduler
+        .lock()
+        .with_current_files(|files| files.remove(fd as usize))
+        .ok_or(SyscallError::BadFileDescriptor)??;
+    drop(file);
+    Ok(0)
+}
+
+// seek(fd, offset, whence)
+fn sys_seek(args: &[u64; 6]) -> SyscallResult {
+    let [fd, offset, whence, ..] = *args;
+    let position = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    Ok(current_file(fd)?.seek(position)?)
+}
+
+// stat(fd, stat)
+fn sys_stat(args: &[u64; 6]) -> SyscallResult {
+    let [fd, stat, ..] = *args;
+    let metadata = current_file(fd)?.stat();
+    let buf = user_slice_mut(stat, core::mem::size_of::<Stat>() as u64)?;
+    let stat = Stat {
+        file_type: match metadata.file_type {
+            FileType::File => 1,
+            FileType::Directory => 2,
+            FileType::CharDevice => 3,
+        },
+        size: metadata.size,
+    };
+    unsafe { (buf.as_mut_ptr() as *mut Stat).write_unaligned(stat) };
+    Ok(0)
+}
+
+// readdir(fd, buf, len)
+// 次のエントリの名前をbufに書き、その長さを返す。最後まで読んだら0
+fn sys_readdir(args: &[u64; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = current_file(fd)?;
+    let buf = user_slice_mut(buf, len)?;
+    let Some(entry) = file.read_dir_next()? else {
+        return Ok(0);
+    };
+    let name = entry.name.as_bytes();
+    if name.len() > buf.len() {
+        // 入りきらなかったエントリは次の呼び出しでもう一度返す
+        file.seek(SeekFrom::Current(-1))?;
+        return Err(SyscallError::InvalidArgument);
+    }
+    buf[..name.len()].copy_from_slice(name);
+    Ok(name.len() as u64)
+}
+
+// mkdir(path, path_len)
+fn sys_mkdir(args: &[u64; 6]) -> SyscallResult {
+    let [path, path_len, ..] = *args;
+    vfs::create(user_str(path, path_len)?, FileType::Directory)?;
+    Ok(0)
+}
+
+// unlink(path, path_len)
+fn sys_unlink(args: &[u64; 6]) -> SyscallResult {
+    let [path, path_len, ..] = *args;
+    vfs::unlink(user_str(path, path_len)?)?;
+    Ok(0)
+}
+
+// rename(old_path, old_path_len, new_path, new_path_len)
+fn sys_rename(args: &[u64; 6]) -> SyscallResult {
+    let [old_path, old_path_len, new_path, new_path_len, ..] = *args;
+    vfs::rename(
+        user_str(old_path, old_path_len)?,
+        user_str(new_path, new_path_len)?,
+    )?;
+    Ok(0)
+}
+
+// truncate(fd, size)
+fn sys_truncate(args: &[u64; 6]) -> SyscallResult {
+    let [fd, size, ..] = *args;
+    current_file(fd)?.truncate(size)?;
+    Ok(0)
+}
+
+// exit(status)
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    scheduler::terminate(args[0] as i32);
+    Ok(0)
+}
+
+// fork()
+// 親には子のIDを、子には0を返す
+fn sys_fork(_args: &[u64; 6]) -> SyscallResult {
+    // syscall_entryはカーネルスタックの一番上にTrapFrameを積んでいる
+    let frame = unsafe {
+        &*((KERNEL_STACK_TOP - core::mem::size_of::<TrapFrame>() as u64) as *const TrapFrame)
+    };
+    let scheduler = SCHEDULER.get().ok_or(SyscallError::InvalidArgument)?;
+    let address_space = scheduler
+        .lock()
+        .current_address_space()
+        .ok_or(SyscallError::InvalidArgument)?;
+    let files = scheduler
+        .lock()
+        .with_current_files(|files| files.clone())
+        .ok_or(SyscallError::InvalidArgument)?;
+    scheduler::reap();
+    // コピーには時間がかかるので、スケジューラのロックを持たずに行う
+    let address_space = address_space
+        .lock()
+        .fork()
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    scheduler
+        .lock()
+        .create_forked_process(frame, address_space, files)
+        .map(|id| id.as_u64())
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+// wait(pid, status)
+// pidが-1ならどの子でもよい。終了した子のIDを返し、statusに終了ステータスを書き込む
+fn sys_wait(args: &[u64; 6]) -> SyscallResult {
+    let [pid, status, ..] = *args;
+    let target = match pid as i64 {
+        -1 => None,
+        pid if pid >= 0 => Some(pid as u64),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    if status != 0 {
+        check_user_buffer(status, 4, true)?;
+    }
+    let scheduler = SCHEDULER.get().ok_or(SyscallError::NoChild)?;
+    loop {
+        // ロックを持ったまま眠ることはできないので、先に結果を取り出す
+        let result = scheduler.lock().wait_child(target);
+        match result {
+            WaitResult::Exited {
+                id,
+                status: exit_status,
+                process,
+            } => {
+                // ロックを手放した後で子のスタックやアドレス空間を解放する
+                drop(process);
+                if status != 0 {
+                    unsafe { (status as *mut i32).write_unaligned(exit_status) };
+                }
+                return Ok(id.as_u64());
+            }
+            WaitResult::NoChildren => return Err(SyscallError::NoChild),
+            // 関係ない子の終了で起きることもあるので、起きたら調べ直す
+            WaitResult::Pending(child_exited) => child_exited.acquire(),
+        }
+    }
+}
+
+// yield()
+fn sys_yield(_args: &[u64; 6]) -> SyscallResult {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.lock().request_reschedule();
+    }
+    Ok(0)
+}
+
+// getpid()
+fn sys_getpid(_args: &[u64; 6]) -> SyscallResult {
+    SCHEDULER
+        .get()
+        .and_then(|scheduler| scheduler.lock().current())
+        .map(|id| id.as_u64())
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+// nice(increment)
+// 実行中のプロセスのniceを変え、新しい値を返す
+fn sys_nice(args: &[u64; 6]) -> SyscallResult {
+    SCHEDULER
+        .get()
+        .and_then(|scheduler| scheduler.lock().nice_current(args[0] as i64))
+        .map(|nice| nice as i64 as u64)
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+// sleep(milliseconds)
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    let ms = args[0];
+    if ms == 0 {
+        return Ok(0);
+    }
+    // 時刻で表せないほど長いときは受け付けない
+    let wake_at = Instant::now()
+        .checked_add(Duration::from_millis(ms))
+        .ok_or(SyscallError::InvalidArgument)?;
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.lock().sleep_current(wake_at);
+    }
+    Ok(0)
+}
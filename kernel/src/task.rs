@@ -7,8 +7,11 @@ use core::{
 
 use alloc::boxed::Box;
 
+use crate::println;
+
 pub mod executor;
 pub mod keyboard;
+pub mod shell;
 pub mod simple_executor;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -19,17 +22,23 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
 
 pub struct Task {
     id: TaskId,
+    name: &'static str,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            name,
             future: Box::pin(future),
         }
     }
@@ -38,3 +47,12 @@ impl Task {
         self.future.as_mut().poll(context)
     }
 }
+
+pub fn register_commands() {
+    shell::register("tasks", "list async kernel tasks", |_args| {
+        println!("{:>4}  NAME", "ID");
+        for (id, name) in executor::tasks() {
+            println!("{:>4}  {}", id.as_u64(), name);
+        }
+    });
+}
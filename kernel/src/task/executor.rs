@@ -1,11 +1,23 @@
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
 use core::future::Future;
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
 
 const MAX_TASKS: usize = 100;
 
+// 実行中のタスクの名前。tasksコマンドで表示する
+static TASK_NAMES: Mutex<BTreeMap<TaskId, &'static str>> = Mutex::new(BTreeMap::new());
+
+pub fn tasks() -> Vec<(TaskId, &'static str)> {
+    TASK_NAMES
+        .lock()
+        .iter()
+        .map(|(id, name)| (*id, *name))
+        .collect()
+}
+
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct Spawner(Arc<ArrayQueue<Task>>);
@@ -13,13 +25,14 @@ impl Spawner {
     pub fn new(capacity: usize) -> Self {
         Self(Arc::new(ArrayQueue::new(capacity)))
     }
-    pub fn add(&self, future: impl Future<Output = ()> + 'static) {
-        let _ = self.0.push(Task::new(future));
+    pub fn add(&self, name: &'static str, future: impl Future<Output = ()> + 'static) {
+        let _ = self.0.push(Task::new(name, future));
     }
 }
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
+    // スラブアロケータのtaskキャッシュから確保されるように、1つずつBoxに入れる
+    tasks: BTreeMap<TaskId, Box<Task>>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     spawner: Spawner,
     waker_cache: BTreeMap<TaskId, Waker>,
@@ -36,7 +49,8 @@ impl Executor {
     }
     fn spawn(&mut self, task: Task) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        TASK_NAMES.lock().insert(task_id, task.name);
+        if self.tasks.insert(task.id, Box::new(task)).is_some() {
             panic!("Task with same ID already in task queue!!");
         }
         self.task_queue.push(task_id).expect("Task queue full!!");
@@ -82,6 +96,7 @@ impl Executor {
                     // task done -> remove it
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    TASK_NAMES.lock().remove(&task_id);
                 }
                 Poll::Pending => {}
             }
@@ -0,0 +1,335 @@
+//! キーボードから入力したコマンドを実行するシェル
+//!
+//! コマンドは各サブシステムがregisterで登録する
+//! 行編集では左右の矢印キー、Home/End、Backspace/Delete、上下の矢印キーでの履歴、Tabでの補完が使える
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use futures_util::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+use super::keyboard::ScancodeStream;
+use crate::{console::CONSOLE, fs::vfs, print, println};
+
+pub mod builtins;
+
+const PROMPT: &str = ">> ";
+const HISTORY_SIZE: usize = 32;
+
+/// コマンドの本体。args[0]はコマンド名
+pub type CommandFn = fn(args: &[&str]);
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub run: CommandFn,
+}
+
+static COMMANDS: Mutex<BTreeMap<&'static str, Command>> = Mutex::new(BTreeMap::new());
+
+/// コマンドを登録する。同じ名前のコマンドがあれば置き換える
+pub fn register(name: &'static str, description: &'static str, run: CommandFn) {
+    COMMANDS.lock().insert(
+        name,
+        Command {
+            name,
+            description,
+            run,
+        },
+    );
+}
+
+/// 登録されているコマンドを名前順に返す
+pub fn commands() -> Vec<Command> {
+    COMMANDS.lock().values().copied().collect()
+}
+
+/// 組み込みのコマンドを登録する
+pub fn init() {
+    builtins::register_commands();
+}
+
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::Ignore,
+    );
+    let mut shell = Shell::new();
+
+    if let Some(console) = CONSOLE.get() {
+        console.lock().set_cursor_visible(true);
+    }
+    print!("{}", PROMPT);
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                shell.handle_key(key);
+            }
+        }
+    }
+}
+
+fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(&name) = args.first() else {
+        return;
+    };
+    // コマンドの中でregisterなどを呼べるように、ロックを外してから実行する
+    let command = COMMANDS.lock().get(name).copied();
+    match command {
+        Some(command) => (command.run)(&args),
+        None => println!("{}: command not found", name),
+    }
+}
+
+struct Shell {
+    // 入力中の行と、その中のカーソルの位置(文字単位)
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    // 履歴を辿っているときの位置と、辿る前に入力していた行
+    history_index: Option<usize>,
+    draft: Vec<char>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_index: None,
+            draft: Vec::new(),
+        }
+    }
+
+    fn handle_key(&mut self, key: DecodedKey) {
+        match key {
+            DecodedKey::Unicode('\n') => self.submit(),
+            DecodedKey::Unicode('\x08') => self.backspace(),
+            DecodedKey::Unicode('\x7f') => self.delete(),
+            DecodedKey::Unicode('\t') => self.complete(),
+            DecodedKey::Unicode(c) if !c.is_control() => self.insert(c),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.move_left(),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_right(),
+            DecodedKey::RawKey(KeyCode::Home) => self.move_home(),
+            DecodedKey::RawKey(KeyCode::End) => self.move_end(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_prev(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(),
+            _ => {}
+        }
+    }
+
+    fn submit(&mut self) {
+        self.move_end();
+        println!();
+        let line: String = self.line.iter().collect();
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+
+        let trimmed = line.trim();
+        if !trimmed.is_empty() {
+            if self.history.last().map(String::as_str) != Some(trimmed) {
+                if self.history.len() == HISTORY_SIZE {
+                    self.history.remove(0);
+                }
+                self.history.push(trimmed.to_string());
+            }
+            execute(trimmed);
+        }
+        print!("{}", PROMPT);
+    }
+
+    fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+        // カーソルより後ろを書き直してから、カーソルの位置に戻る
+        self.redraw_from(self.cursor - 1, 0);
+    }
+
+    fn insert_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.insert(c);
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.cursor -= 1;
+        self.line.remove(self.cursor);
+        print!("\x08");
+        self.redraw_from(self.cursor, 1);
+    }
+
+    fn delete(&mut self) {
+        if self.cursor == self.line.len() {
+            return;
+        }
+        self.line.remove(self.cursor);
+        self.redraw_from(self.cursor, 1);
+    }
+
+    // line[start..]を書き直し、消えたerased文字分を空白で消してからself.cursorの位置に戻る
+    fn redraw_from(&self, start: usize, erased: usize) {
+        let mut output: String = self.line[start..].iter().collect();
+        output.extend(core::iter::repeat(' ').take(erased));
+        let back = self.line.len() + erased - self.cursor;
+        output.extend(core::iter::repeat('\x08').take(back));
+        print!("{}", output);
+    }
+
+    fn move_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            print!("\x08");
+        }
+    }
+
+    fn move_right(&mut self) {
+        if self.cursor < self.line.len() {
+            print!("{}", self.line[self.cursor]);
+            self.cursor += 1;
+        }
+    }
+
+    fn move_home(&mut self) {
+        print!("{}", "\x08".repeat(self.cursor));
+        self.cursor = 0;
+    }
+
+    fn move_end(&mut self) {
+        let rest: String = self.line[self.cursor..].iter().collect();
+        print!("{}", rest);
+        self.cursor = self.line.len();
+    }
+
+    // 入力中の行をlineで置き換える
+    fn replace_line(&mut self, line: Vec<char>) {
+        self.move_home();
+        let erased = self.line.len().saturating_sub(line.len());
+        self.line = line;
+        self.cursor = self.line.len();
+        self.redraw_from(0, erased);
+    }
+
+    fn history_prev(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        self.replace_line(self.history[index].chars().collect());
+    }
+
+    fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.replace_line(self.history[index + 1].chars().collect());
+        } else {
+            self.history_index = None;
+            let draft = core::mem::take(&mut self.draft);
+            self.replace_line(draft);
+        }
+    }
+
+    // 先頭の単語はコマンド名で、それ以降はファイルのパスで補完する
+    fn complete(&mut self) {
+        let word_start = self.line[..self.cursor]
+            .iter()
+            .rposition(|c| *c == ' ')
+            .map_or(0, |index| index + 1);
+        let word: String = self.line[word_start..self.cursor].iter().collect();
+        let is_command = self.line[..word_start].iter().all(|c| *c == ' ');
+
+        let (prefix, candidates) = if is_command {
+            let names = commands()
+                .iter()
+                .filter(|command| command.name.starts_with(word.as_str()))
+                .map(|command| command.name.to_string() + " ")
+                .collect();
+            (word.len(), names)
+        } else {
+            complete_path(&word)
+        };
+
+        match candidates.as_slice() {
+            [] => {}
+            [candidate] => self.insert_str(&candidate[prefix..]),
+            _ => {
+                let common = common_prefix(&candidates);
+                if common.len() > prefix {
+                    self.insert_str(&common[prefix..]);
+                    return;
+                }
+                // 候補を一覧にしてから入力中の行を表示し直す
+                self.move_end();
+                println!();
+                for candidate in &candidates {
+                    print!("{}  ", candidate.trim_end());
+                }
+                println!();
+                let line: String = self.line.iter().collect();
+                print!("{}{}", PROMPT, line);
+                self.cursor = self.line.len();
+            }
+        }
+    }
+}
+
+// wordをパスとして補完する候補を返す
+// 候補はディレクトリの部分を含めたパスで、ディレクトリには"/"を付ける
+fn complete_path(word: &str) -> (usize, Vec<String>) {
+    let Some(slash) = word.rfind('/') else {
+        return (word.len(), Vec::new());
+    };
+    let (directory, name) = word.split_at(slash + 1);
+    let entries = vfs::resolve(directory).and_then(|dentry| dentry.inode().read_dir());
+    let Ok(entries) = entries else {
+        return (word.len(), Vec::new());
+    };
+    let candidates = entries
+        .into_iter()
+        .filter(|entry| entry.name.starts_with(name))
+        .map(|entry| {
+            let suffix = if entry.file_type == vfs::FileType::Directory {
+                "/"
+            } else {
+                " "
+            };
+            String::from(directory) + &entry.name + suffix
+        })
+        .collect();
+    (word.len(), candidates)
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = candidates[0].clone();
+    for candidate in &candidates[1..] {
+        let len = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((index, c), _)| index + c.len_utf8());
+        prefix.truncate(len);
+    }
+    prefix
+}
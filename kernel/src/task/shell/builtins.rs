@@ -0,0 +1,77 @@
+//! シェルの組み込みコマンド
+
+use core::arch::asm;
+
+use x86_64::{instructions::port::Port, structures::DescriptorTablePointer, VirtAddr};
+
+use super::{commands, register};
+use crate::{console::CONSOLE, println, time};
+
+pub fn register_commands() {
+    register("help", "show available commands", help);
+    register("clear", "clear the screen", clear);
+    register("echo", "print arguments", echo);
+    register("uptime", "show time since boot", uptime);
+    register("reboot", "restart the machine", reboot);
+}
+
+fn help(_args: &[&str]) {
+    let commands = commands();
+    let width = commands
+        .iter()
+        .map(|command| command.name.len())
+        .max()
+        .unwrap_or(0);
+    for command in commands {
+        println!(
+            "{:width$}  {}",
+            command.name,
+            command.description,
+            width = width
+        );
+    }
+}
+
+fn clear(_args: &[&str]) {
+    if let Some(console) = CONSOLE.get() {
+        console.lock().clear();
+    }
+}
+
+fn echo(args: &[&str]) {
+    let mut words = args[1..].iter();
+    if let Some(first) = words.next() {
+        crate::print!("{}", first);
+    }
+    for word in words {
+        crate::print!(" {}", word);
+    }
+    println!();
+}
+
+fn uptime(_args: &[&str]) {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    println!(
+        "up {}:{:02}:{:02}.{:06} ({} ticks)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        uptime.subsec_micros(),
+        time::ticks()
+    );
+}
+
+fn reboot(_args: &[&str]) {
+    println!("rebooting...");
+    unsafe {
+        // キーボードコントローラにCPUのリセットを要求する
+        Port::<u8>::new(0x64).write(0xfe);
+        // 効かなければ空のIDTで例外を起こし、トリプルフォールトでリセットする
+        x86_64::instructions::tables::lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3", options(noreturn));
+    }
+}
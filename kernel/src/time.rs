@@ -0,0 +1,108 @@
+//! 起動してからの時間
+//!
+//! PITを決まった周波数で割り込ませてティックを数え、ティックより細かいところはTSCで補う
+//! TSCの周波数は起動時にPITと比べて測る
+
+use core::{
+    ops::Add,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub use core::time::Duration;
+
+use crate::println;
+
+mod pit;
+mod tsc;
+
+/// タイマ割り込みの周波数
+pub const TICK_HZ: u64 = 100;
+// 実際の周波数はBASE_FREQUENCY / TICK_DIVISORで、TICK_HZから少しずれる
+const TICK_DIVISOR: u64 = (pit::BASE_FREQUENCY + TICK_HZ / 2) / TICK_HZ;
+const NANOS_PER_TICK: u64 = TICK_DIVISOR * 1_000_000_000 / pit::BASE_FREQUENCY;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// 最後にティックを数えたときのTSC
+static TICK_TSC: AtomicU64 = AtomicU64::new(0);
+// 測れなかったときは0のままで、ティック単位でしか分からない
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// PITの周波数を設定し、TSCの周波数を測る
+/// タイマ割り込みを許可する前に呼ぶ
+pub fn init() {
+    pit::start_periodic(TICK_DIVISOR as u16);
+    let tsc_hz = tsc::calibrate();
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    TICK_TSC.store(tsc::read(), Ordering::Relaxed);
+    println!(
+        "timer: {} Hz, TSC {}.{:03} MHz",
+        TICK_HZ,
+        tsc_hz / 1_000_000,
+        tsc_hz / 1000 % 1000
+    );
+}
+
+/// タイマ割り込みから呼ばれ、ティックを1つ進める
+pub fn tick() {
+    // 読む側はTICKSが変わっていないことで、TICK_TSCが同じティックのものか確かめる
+    TICK_TSC.store(tsc::read(), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks.saturating_mul(TICK_DIVISOR * 1000) / pit::BASE_FREQUENCY
+}
+
+// 起動してからのナノ秒
+fn now_nanos() -> u64 {
+    let (count, tick_tsc, now) = loop {
+        let count = ticks();
+        let tick_tsc = TICK_TSC.load(Ordering::Relaxed);
+        let now = tsc::read();
+        if count == ticks() {
+            break (count, tick_tsc, now);
+        }
+    };
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    let since_tick = match tsc_hz {
+        0 => 0,
+        hz => (now.saturating_sub(tick_tsc) as u128 * 1_000_000_000 / hz as u128) as u64,
+    };
+    // 割り込みが遅れてもティックの境目を越えないようにして、時間が戻らないようにする
+    count * NANOS_PER_TICK + since_tick.min(NANOS_PER_TICK - 1)
+}
+
+/// 起動してからの時間
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now_nanos())
+}
+
+/// 単調に増える時刻。起動してからのナノ秒で持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(now_nanos())
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
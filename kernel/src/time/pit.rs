@@ -0,0 +1,56 @@
+//! 8253/8254 PIT(Programmable Interval Timer)
+//!
+//! チャネル0はIRQ0につながっていて、タイマ割り込みに使う
+//! チャネル2はゲートをソフトウェアから操作できるので、TSCを測るのに使う
+
+use core::hint;
+
+use x86_64::instructions::port::Port;
+
+/// PITのカウンタが数える周波数
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// bit0: チャネル2のゲート、bit1: スピーカー、bit5: チャネル2の出力
+const GATE: u16 = 0x61;
+
+// コマンドのビット
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_SQUARE_WAVE: u8 = 0b011 << 1;
+
+/// チャネル0がBASE_FREQUENCY / divisorの周波数で割り込むようにする
+/// 0は65536として扱われる
+pub fn start_periodic(divisor: u16) {
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL_0 | ACCESS_LOW_HIGH | MODE_SQUARE_WAVE);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+}
+
+/// チャネル2でcountだけ数え終わるまで待つ
+/// 割り込みは使わず、出力が立つのをポーリングする
+pub fn wait(count: u16) {
+    unsafe {
+        let mut gate = Port::<u8>::new(GATE);
+        let saved = gate.read();
+        // スピーカーを止めてからゲートを開ける
+        gate.write((saved & !0b10) | 0b01);
+        Port::<u8>::new(COMMAND)
+            .write(SELECT_CHANNEL_2 | ACCESS_LOW_HIGH | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+        // モード0ではカウントが0になると出力が立つ
+        while gate.read() & 0b10_0000 == 0 {
+            hint::spin_loop();
+        }
+        gate.write(saved);
+    }
+}
@@ -0,0 +1,24 @@
+//! TSC(Time Stamp Counter)
+//!
+//! 起動してからのCPUのクロック数で、ティックより細かい時間を測るのに使う
+//! 周波数が変わらないinvariant TSCであることを前提にしている
+
+use core::arch::x86_64::_rdtsc;
+
+use super::pit;
+
+// 測る時間。長いほど正確になるが、その分起動が遅れる
+const CALIBRATION_MS: u64 = 10;
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// PITのチャネル2でCALIBRATION_MSだけ待つ間にTSCがいくつ進んだかで、TSCの周波数を測る
+pub fn calibrate() -> u64 {
+    let count = pit::BASE_FREQUENCY * CALIBRATION_MS / 1000;
+    let start = read();
+    pit::wait(count as u16);
+    let end = read();
+    (end - start) * pit::BASE_FREQUENCY / count
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+use userland::{println, syscall, Args};
+
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    let pid = syscall::getpid();
+    println!(
+        "{}: hello from user space (pid {})",
+        args.get(0).unwrap_or("init"),
+        pid
+    );
+    print_file("/etc/motd");
+    fork_child(pid);
+    for i in 0..3 {
+        syscall::sleep(1000);
+        println!("pid {}: tick {}", pid, i);
+    }
+    0
+}
+
+fn print_file(path: &str) {
+    let fd = syscall::open(path, syscall::O_RDONLY);
+    if fd < 0 {
+        println!("init: cannot open {} (error {})", path, -fd);
+        return;
+    }
+    let mut buf = [0; 128];
+    loop {
+        let len = syscall::read(fd as u64, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        syscall::write(syscall::STDOUT, &buf[..len as usize]);
+    }
+    syscall::close(fd as u64);
+}
+
+// 子プロセスを1つ作り、その終了ステータスを受け取る
+fn fork_child(pid: u64) {
+    let child = syscall::fork();
+    if child < 0 {
+        println!("init: fork failed (error {})", -child);
+        return;
+    }
+    if child == 0 {
+        println!("pid {}: forked from pid {}", syscall::getpid(), pid);
+        syscall::exit(42);
+    }
+    let mut status = 0;
+    let waited = syscall::wait(child, &mut status);
+    if waited < 0 {
+        println!("init: wait failed (error {})", -waited);
+        return;
+    }
+    println!(
+        "pid {}: child {} exited with status {}",
+        pid, waited, status
+    );
+}
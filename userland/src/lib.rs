@@ -0,0 +1,90 @@
+//! ROSTの上で動くユーザプログラムのためのライブラリ
+//!
+//! `_start`でスタックからargc/argv/envpを取り出し、各プログラムの`main`を呼ぶ
+
+#![no_std]
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+pub mod io;
+pub mod syscall;
+
+/// プログラムに渡された引数と環境変数
+pub struct Args {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'static str> {
+        if index >= self.argc {
+            return None;
+        }
+        unsafe { Some(c_str(*self.argv.add(index))) }
+    }
+
+    pub fn env(&self) -> impl Iterator<Item = &'static str> {
+        let mut envp = self.envp;
+        core::iter::from_fn(move || unsafe {
+            if (*envp).is_null() {
+                return None;
+            }
+            let value = c_str(*envp);
+            envp = envp.add(1);
+            Some(value)
+        })
+    }
+}
+
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+}
+
+// エントリ時のrspはargcを指している
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+extern "Rust" {
+    // 各プログラムが#[no_mangle]で定義する
+    fn main(args: Args) -> i32;
+}
+
+extern "C" fn start(stack: *const u64) -> ! {
+    let args = unsafe {
+        let argc = *stack as usize;
+        let argv = stack.add(1) as *const *const u8;
+        Args {
+            argc,
+            argv,
+            envp: argv.add(argc + 1),
+        }
+    };
+    let status = unsafe { main(args) };
+    syscall::exit(status)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    syscall::exit(101)
+}
@@ -0,0 +1,214 @@
+//! カーネルのシステムコールの薄いラッパ
+//! 番号と呼び出し規約はカーネルのsyscallモジュールと合わせる
+
+use core::arch::asm;
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_GETPID: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_READ: u64 = 5;
+pub const SYS_OPEN: u64 = 6;
+pub const SYS_CLOSE: u64 = 7;
+pub const SYS_SEEK: u64 = 8;
+pub const SYS_STAT: u64 = 9;
+pub const SYS_READDIR: u64 = 10;
+pub const SYS_MKDIR: u64 = 11;
+pub const SYS_UNLINK: u64 = 12;
+pub const SYS_RENAME: u64 = 13;
+pub const SYS_TRUNCATE: u64 = 14;
+pub const SYS_FORK: u64 = 15;
+pub const SYS_WAIT: u64 = 16;
+pub const SYS_NICE: u64 = 17;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// openのフラグ
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+// seekの基準位置
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// エラー番号
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+
+pub const FILE_TYPE_FILE: u64 = 1;
+pub const FILE_TYPE_DIRECTORY: u64 = 2;
+pub const FILE_TYPE_CHAR_DEVICE: u64 = 3;
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Stat {
+    pub file_type: u64,
+    pub size: u64,
+}
+
+unsafe fn syscall0(number: u64) -> u64 {
+    let ret;
+    asm!("syscall", inlateout("rax") number => ret, out("rcx") _, out("r11") _, options(nostack));
+    ret
+}
+
+unsafe fn syscall1(number: u64, arg0: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+unsafe fn syscall2(number: u64, arg0: u64, arg1: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+unsafe fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+unsafe fn syscall4(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+// 負の値はエラー番号
+pub fn write(fd: u64, buf: &[u8]) -> i64 {
+    unsafe { syscall3(SYS_WRITE, fd, buf.as_ptr() as u64, buf.len() as u64) as i64 }
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> i64 {
+    unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) as i64 }
+}
+
+pub fn open(path: &str, flags: u64) -> i64 {
+    unsafe { syscall3(SYS_OPEN, path.as_ptr() as u64, path.len() as u64, flags) as i64 }
+}
+
+pub fn close(fd: u64) -> i64 {
+    unsafe { syscall1(SYS_CLOSE, fd) as i64 }
+}
+
+pub fn seek(fd: u64, offset: i64, whence: u64) -> i64 {
+    unsafe { syscall3(SYS_SEEK, fd, offset as u64, whence) as i64 }
+}
+
+pub fn stat(fd: u64, stat: &mut Stat) -> i64 {
+    unsafe { syscall2(SYS_STAT, fd, stat as *mut Stat as u64) as i64 }
+}
+
+// 次のエントリの名前をbufに書き、その長さを返す。最後まで読んだら0
+pub fn readdir(fd: u64, buf: &mut [u8]) -> i64 {
+    unsafe { syscall3(SYS_READDIR, fd, buf.as_mut_ptr() as u64, buf.len() as u64) as i64 }
+}
+
+pub fn mkdir(path: &str) -> i64 {
+    unsafe { syscall2(SYS_MKDIR, path.as_ptr() as u64, path.len() as u64) as i64 }
+}
+
+pub fn unlink(path: &str) -> i64 {
+    unsafe { syscall2(SYS_UNLINK, path.as_ptr() as u64, path.len() as u64) as i64 }
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> i64 {
+    unsafe {
+        syscall4(
+            SYS_RENAME,
+            old_path.as_ptr() as u64,
+            old_path.len() as u64,
+            new_path.as_ptr() as u64,
+            new_path.len() as u64,
+        ) as i64
+    }
+}
+
+pub fn truncate(fd: u64, size: u64) -> i64 {
+    unsafe { syscall2(SYS_TRUNCATE, fd, size) as i64 }
+}
+
+pub fn exit(status: i32) -> ! {
+    unsafe {
+        syscall1(SYS_EXIT, status as u64);
+    }
+    // exitしたプロセスは二度とスケジュールされない
+    loop {
+        yield_now();
+    }
+}
+
+// 親には子のID、子には0を返す
+pub fn fork() -> i64 {
+    unsafe { syscall0(SYS_FORK) as i64 }
+}
+
+// 子が終了するまで待ち、そのIDを返す。pidが-1ならどの子でもよい
+pub fn wait(pid: i64, status: &mut i32) -> i64 {
+    unsafe { syscall2(SYS_WAIT, pid as u64, status as *mut i32 as u64) as i64 }
+}
+
+// niceをincrementだけ変え、新しい値を返す
+pub fn nice(increment: i64) -> i64 {
+    unsafe { syscall1(SYS_NICE, increment as u64) as i64 }
+}
+
+pub fn yield_now() {
+    unsafe {
+        syscall0(SYS_YIELD);
+    }
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+pub fn sleep(ms: u64) {
+    unsafe {
+        syscall1(SYS_SLEEP, ms);
+    }
+}